@@ -0,0 +1,98 @@
+use bevy::prelude::{Color, Resource};
+
+use crate::derivation::{CellChange, Derivation};
+use crate::grammar::{Key, Rule};
+use crate::terminal::TerminalEvent;
+
+/// Key passing a tick of each clock in turn, until one of them applies a rule.
+pub const STEP_KEY: Key = Key::Function(10);
+
+// candidates listed at most, the others are counted
+const MAX_CANDIDATES: usize = 8;
+
+// palette indices of the background of the region of a rule applied and of its nonterminal
+const REGION_BACK: u8 = 4;
+const ANCHOR_BACK: u8 = 5;
+
+fn header(derivation: &Derivation, position: (usize, usize), rule: &Rule) -> String {
+    let source = derivation.grammar.sources.get(rule.source).map(|s| s.as_str()).unwrap_or("");
+    format!("={} at {},{} ({}:{}) weight {}", rule.lhs_all, position.0, position.1, source, rule.line, rule.weight)
+}
+
+/// Lines describing a step: the rule applied with its anchors, and the candidates the key
+/// chose from with their weights, the chosen ones marked with `*`.
+pub fn describe(derivation: &Derivation,
+                key: Key,
+                applied: &[((usize, usize), Rule)],
+                candidates: &[((usize, usize), Rule)],
+) -> Vec<String> {
+    let mut lines = Vec::<String>::default();
+    if let Some((position, rule)) = applied.first() {
+        lines.push(format!("key {}: {}", key, header(derivation, *position, rule)));
+        lines.push(format!("  anchors o {},{}  m {},{}  q {},{}",
+                           rule.ro, rule.co, rule.rm, rule.cm, rule.rq, rule.cq));
+        if applied.len() > 1 {
+            lines.push(format!("  and {} more applied at once", applied.len() - 1));
+        }
+    }
+    let weight_sum: i32 = candidates.iter().map(|(_, rule)| rule.weight).sum();
+    lines.push(format!("{} candidate(s), total weight {}", candidates.len(), weight_sum));
+    for (position, rule) in candidates.iter().take(MAX_CANDIDATES) {
+        // variants of a rule share its header and line, their keys tell them apart
+        let chosen = applied.iter()
+            .any(|(p, r)| (p, &r.lhs_all, r.line, r.key) == (position, &rule.lhs_all, rule.line, rule.key));
+        lines.push(format!("{} {}", if chosen { '*' } else { ' ' }, header(derivation, *position, rule)));
+    }
+    if candidates.len() > MAX_CANDIDATES {
+        lines.push(format!("  ... {} more", candidates.len() - MAX_CANDIDATES));
+    }
+    lines
+}
+
+/// Debug mode (`--debug`): pauses after each step applying a rule, highlights the region it
+/// matched and wrote and lists its anchors and the other candidates.
+#[derive(Resource)]
+pub struct Debugger {
+    pub enabled: bool,
+    // cells drawn over the grid, shown as derived again when the derivation goes on
+    covered: Vec<(usize, usize)>,
+}
+
+impl Debugger {
+    pub fn new(enabled: bool) -> Self {
+        Debugger { enabled, covered: vec![] }
+    }
+
+    /// Draws the cells covered by the last step shown as they are.
+    pub fn restore(&mut self, derivation: &Derivation) -> Vec<TerminalEvent> {
+        let events = derivation.redraw(&self.covered).iter().map(TerminalEvent::from).collect();
+        self.covered.clear();
+        events
+    }
+
+    /// Highlights the region of the rules applied by a step and shows its description in a
+    /// panel, at the bottom of the grid unless the region is in the lower half.
+    pub fn show(&mut self, derivation: &Derivation, lines: &[String], applied: &[((usize, usize), Rule)]) -> Vec<TerminalEvent> {
+        let mut events = self.restore(derivation);
+        let (rows, cols) = derivation.size();
+        let mut rows_used = Vec::<usize>::default();
+        for (position, rule) in applied {
+            for (row, col) in derivation.region(*position, rule) {
+                let (c, fore, _) = derivation.cell(row, col);
+                let back = if (row, col) == *position { ANCHOR_BACK } else { REGION_BACK };
+                events.push(TerminalEvent::from(&CellChange { row, col, c, fore, back }));
+                self.covered.push((row, col));
+                rows_used.push(row);
+            }
+        }
+        let height = lines.len().min(rows.saturating_sub(1));
+        let lower = rows_used.iter().sum::<usize>() > rows_used.len() * rows / 2;
+        let top = if lower { 1 } else { rows - height };
+        for (i, line) in lines.iter().take(height).enumerate() {
+            let text = format!(" {}", line).chars().chain(std::iter::repeat(' ')).take(cols).collect::<String>();
+            events.push(TerminalEvent { row: top + i, col: 0, s: text, attr: (Color::WHITE, Color::BLACK) });
+            self.covered.extend((0..cols).map(|col| (top + i, col)));
+        }
+        events
+    }
+}
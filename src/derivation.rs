@@ -1,15 +1,14 @@
 use std::default::Default;
-use std::collections::HashMap;
-use bevy::color::palettes::basic;
-use bevy::prelude::Color;
-use bevy::prelude::Component;
-use bevy::utils::HashSet;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 
-use crate::grammar::Rule;
+use crate::grammar::{GameEnd, Rule};
 use crate::grammar::Grammar2D;
-use crate::terminal::TerminalEvent;
+use crate::grammar::Key;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, PartialEq)]
 struct G {
     c: char,
     fore: u8,
@@ -17,63 +16,176 @@ struct G {
     z_ord: u8,
 }
 
-#[derive(Component)]
+impl G {
+    const BLANK: G = G { c: ' ', fore: 7, back: 0, z_ord: b'a' };
+
+    // four characters per cell: the symbol, the colours as digits and the z order
+    fn encode(&self) -> String {
+        let digit = |n: u8| char::from_digit(n as u32, 36).unwrap_or('0');
+        [self.c, digit(self.fore), digit(self.back), self.z_ord as char].iter().collect()
+    }
+
+    fn decode(s: &str) -> Option<Vec<G>> {
+        let chars = s.chars().collect::<Vec<_>>();
+        if chars.len() % 4 != 0 {
+            return None;
+        }
+        chars.chunks(4).map(|g| Some(G {
+            c: g[0],
+            fore: g[1].to_digit(36)? as u8,
+            back: g[2].to_digit(36)? as u8,
+            z_ord: u8::try_from(g[3] as u32).ok()?,
+        })).collect()
+    }
+}
+
+/// Cell shown differently after a step. Colours are palette indices: 0 black, 1 red,
+/// 2 green, 3 yellow, 4 blue, 5 magenta, 6 cyan and 7 white.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CellChange {
+    pub row: usize,
+    pub col: usize,
+    pub c: char,
+    pub fore: u8,
+    pub back: u8,
+}
+
+// applicable rules of a key: nonterminal positions with indices into the rules of their symbol
+type Matches = BTreeSet<((usize, usize), usize)>;
+
+// rules with this key apply whatever key is passed
+const MAGIC: Key = Key::Char('?');
+
 pub struct Derivation {
     pub grammar: Grammar2D,
     rows: i32, cols: i32,
     current: Vec<Vec<G>>,
     memory: Vec<Vec<G>>,
     x: HashMap<(usize, usize), char>,
-    colours: Vec<Color>,
+    seed: u64,
+    rng: StdRng,
+    matches: HashMap<Key, Matches>,
+    // cells read by any rule, relative to the position of its nonterminal
+    reads: Vec<(i32, i32)>,
+    undo: VecDeque<Snapshot>,
+    variables: HashMap<String, i64>,
+    // score, time and errors of the game, kept by the frontend
+    reward: (i64, i64, i64),
+}
+
+/// State restored by undoing a player step.
+pub struct Snapshot {
+    current: Vec<Vec<G>>,
+    memory: Vec<Vec<G>>,
+    x: HashMap<(usize, usize), char>,
+    variables: HashMap<String, i64>,
+    score: i64,
+    errors: i64,
 }
 
 pub struct DerivationResult {
-    pub terminal_events: Vec<TerminalEvent>,
+    pub changes: Vec<CellChange>,
     pub score_delta: i32,
     pub errors_delta: i32,
     pub dbg_rule: String,
     pub sound_alias: char,
+    /// Rules applied with the positions of their nonterminals.
+    pub applied: Vec<((usize, usize), Rule)>,
+    /// Outcome of the game declared by a rule applied.
+    pub end: Option<GameEnd>,
 }
 impl Default for DerivationResult {
     fn default() -> Self {
         DerivationResult {
-            terminal_events: Default::default(),
+            changes: Default::default(),
             score_delta: 0,
             errors_delta: 0,
             dbg_rule: "".to_string(),
             sound_alias: ' ',
+            applied: vec![],
+            end: None,
         }
     }
 }
 impl Derivation {
-    pub fn new(grammar: Grammar2D, rows: usize, cols: usize) -> Self {
+    /// Derivation whose random choices are all drawn from a generator seeded with `seed`,
+    /// so that the same seed and input reproduce the same run.
+    pub fn new(grammar: Grammar2D, rows: usize, cols: usize, seed: u64) -> Self {
         Derivation {
+            variables: grammar.variables.iter().cloned().collect(),
             grammar,
             rows: rows as i32,
             cols: cols as i32,
-            current: vec![vec![G { c: ' ', fore: 7, back: 0, z_ord: 'a' as u8 }; cols]; rows],
-            memory:  vec![vec![G { c: ' ', fore: 7, back: 0, z_ord: 'a' as u8 }; cols]; rows],
+            current: vec![vec![G::BLANK; cols]; rows],
+            memory:  vec![vec![G::BLANK; cols]; rows],
             x: Default::default(),
-            colours: vec![
-                Color::Srgba(basic::BLACK),
-                Color::Srgba(basic::RED),
-                Color::Srgba(basic::GREEN),
-                Color::Srgba(basic::YELLOW),
-                Color::Srgba(basic::BLUE),
-                Color::Srgba(basic::FUCHSIA),
-                Color::Srgba(basic::AQUA),
-                Color::Srgba(basic::WHITE),
-            ]
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            matches: Default::default(),
+            reads: Default::default(),
+            undo: Default::default(),
+            reward: (0, 0, 0),
         }
     }
 
-    //TODO refactor bevy deps out of Derivation
-    fn get_color(&self, fore: u8, back: u8) -> (Color, Color) {
-        (self.colours[fore.try_into().unwrap_or(7)], self.colours[back.try_into().unwrap_or(0)])
+    /// Continues the derivation with the rules of another grammar. Variables still declared
+    /// keep their values.
+    pub fn set_grammar(&mut self, grammar: Grammar2D) {
+        self.variables = grammar.variables.iter()
+            .map(|(name, value)| (name.clone(), *self.variables.get(name).unwrap_or(value)))
+            .collect();
+        self.grammar = grammar;
+        self.rematch();
     }
-    pub fn start(&mut self) -> Vec<TerminalEvent> {
-        let colour = self.get_color(7, 0);
-        self.grammar.seeds.iter().map(
+
+    /// Variables with their values, in declaration order.
+    pub fn variables(&self) -> Vec<(&str, i64)> {
+        self.grammar.variables.iter()
+            .map(|(name, value)| (name.as_str(), *self.variables.get(name).unwrap_or(value)))
+            .collect()
+    }
+
+    /// Score, time and errors of the game, which conditions of rules may test.
+    pub fn set_reward(&mut self, score: i64, time: i64, errors: i64) {
+        self.reward = (score, time, errors);
+    }
+
+    fn conditions_hold(&self, rule: &Rule) -> bool {
+        rule.conditions.iter().all(|condition| {
+            let value = match condition.variable.as_str() {
+                "score" => Some(self.reward.0),
+                "time" => Some(self.reward.1),
+                "errors" => Some(self.reward.2),
+                name => self.variables.get(name).copied(),
+            };
+            value.is_some_and(|value| condition.holds(value))
+        })
+    }
+
+    fn update_variables(&mut self, rule: &Rule) {
+        for effect in rule.effects.iter() {
+            if let Some(value) = self.variables.get_mut(&effect.variable) {
+                *value = effect.apply(*value);
+            }
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows as usize, self.cols as usize)
+    }
+
+    /// Character shown at a cell with its foreground and background colour numbers.
+    pub fn cell(&self, row: usize, col: usize) -> (char, u8, u8) {
+        let g = self.current[row][col];
+        (g.c, g.fore, g.back)
+    }
+
+    pub fn start(&mut self) -> Vec<CellChange> {
+        let changes = self.grammar.seeds.iter().map(
             |seed| {
                 let col = match seed.lr {
                     'l' => 0,
@@ -81,8 +193,8 @@ impl Derivation {
                     'c' => (self.cols / 2) as usize,
                     'R' => 2*((self.cols - 1)/2) as usize,
                     'C' => 2*((self.cols / 2)/2) as usize,
-                    'X' => 2*((rand::random::<usize>() % (self.cols as usize))/2),
-                    _ => rand::random::<usize>() % (self.cols as usize),
+                    'X' => 2*((self.rng.gen::<usize>() % (self.cols as usize))/2),
+                    _ => self.rng.gen::<usize>() % (self.cols as usize),
                 };
                 let row = match seed.ul {
                     'u' => 1,
@@ -90,8 +202,8 @@ impl Derivation {
                     'c' => (self.rows / 2) as usize,
                     'L' => 2*((self.rows - 2)/2) as usize,
                     'C' => 2*((self.rows / 2 - 1)/2) as usize,
-                    'X' => 2*((rand::random::<usize>() % ((self.rows - 1) as usize))/2),
-                    _ => rand::random::<usize>() % ((self.rows - 1) as usize)  + 1,
+                    'X' => 2*((self.rng.gen::<usize>() % ((self.rows - 1) as usize))/2),
+                    _ => self.rng.gen::<usize>() % ((self.rows - 1) as usize)  + 1,
                 };
                 self.x.insert((row, col), seed.c);
 
@@ -103,14 +215,222 @@ impl Derivation {
                 cursor.back = 0;
                 cursor.z_ord = 'a' as u8;
 
-                TerminalEvent {
+                CellChange {
                     row,
                     col,
-                    s: seed.c.to_string(),
-                    attr: colour,
+                    c: seed.c,
+                    fore: 7,
+                    back: 0,
+                }
+            }
+        ).collect();
+        self.rematch();
+        changes
+    }
+
+    /// Cells read by a rule relative to the position of its nonterminal.
+    fn rule_reads(rule: &Rule) -> Vec<(i32, i32)> {
+        let horizontal = rule.cq > rule.co;
+        let mut reads = Vec::<(i32, i32)>::default();
+        for (r, row) in rule.rhs_all.split('\n').enumerate() {
+            let r = r as i32;
+            if !horizontal && r >= rule.rm {
+                break;
+            }
+            for (c, p) in row.chars().enumerate() {
+                let c = c as i32;
+                if p != ' ' && (!horizontal || c < rule.cm) {
+                    reads.push((r - rule.ro, c - rule.co));
+                }
+            }
+        }
+        reads
+    }
+
+    /// State before a step, with the score and errors of the game at that point.
+    pub fn snapshot(&self, score: i64, errors: i64) -> Snapshot {
+        Snapshot {
+            current: self.current.clone(),
+            memory: self.memory.clone(),
+            x: self.x.clone(),
+            variables: self.variables.clone(),
+            score,
+            errors,
+        }
+    }
+
+    /// Remembers a state to go back to, keeping as many as the undo depth of the grammar.
+    pub fn push_undo(&mut self, snapshot: Snapshot) {
+        let depth = self.grammar.undo.map(|(_, depth)| depth).unwrap_or(0);
+        self.undo.push_back(snapshot);
+        while self.undo.len() > depth {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Goes back to the last state remembered; returns its score and errors with every cell
+    /// to redraw.
+    pub fn undo(&mut self) -> Option<(i64, i64, Vec<CellChange>)> {
+        let Snapshot { current, memory, x, variables, score, errors } = self.undo.pop_back()?;
+        self.current = current;
+        self.memory = memory;
+        self.x = x;
+        self.variables = variables;
+        self.rematch();
+        Some((score, errors, self.cells()))
+    }
+
+    /// Cells as they are, to draw them again over anything drawn on top of them.
+    pub fn redraw(&self, cells: &[(usize, usize)]) -> Vec<CellChange> {
+        let (rows, cols) = self.size();
+        cells.iter()
+            .filter(|&&(row, col)| row < rows && col < cols)
+            .map(|&(row, col)| {
+                let (c, fore, back) = self.cell(row, col);
+                CellChange { row, col, c, fore, back }
+            })
+            .collect()
+    }
+
+    /// Every cell below the status line.
+    fn cells(&self) -> Vec<CellChange> {
+        self.current.iter().enumerate().skip(1).flat_map(|(row, cells)| {
+            cells.iter().enumerate().map(move |(col, g)| CellChange {
+                row, col, c: g.c, fore: g.fore, back: g.back,
+            })
+        }).collect()
+    }
+
+    /// State of the derivation as lines of text: the grid, the memory layer, the nonterminal
+    /// positions and the random generator, which is reseeded so that this derivation and the
+    /// one loaded from the lines continue alike. Blank cells at the end of rows are left out.
+    pub fn save(&mut self) -> Vec<String> {
+        let next = self.rng.gen::<u64>();
+        self.rng = StdRng::seed_from_u64(next);
+        let mut lines = vec![
+            format!("seed {}", self.seed),
+            format!("rng {}", next),
+            format!("size {} {}", self.rows, self.cols),
+        ];
+        let mut x = self.x.iter().collect::<Vec<_>>();
+        x.sort();
+        lines.extend(x.into_iter().map(|((row, col), c)| format!("x {} {} {}", row, col, c)));
+        lines.extend(self.variables().into_iter().map(|(name, value)| format!("var {} {}", name, value)));
+        for (name, grid) in [("current", &self.current), ("memory", &self.memory)] {
+            for (row, cells) in grid.iter().enumerate() {
+                let used = cells.iter().rposition(|g| *g != G::BLANK).map_or(0, |col| col + 1);
+                if used > 0 {
+                    lines.push(format!("{} {} {}", name, row, cells[..used].iter().map(G::encode).collect::<String>()));
+                }
+            }
+        }
+        lines
+    }
+
+    /// Puts back a state written by `save`, on a grid of any size it fits in; returns every
+    /// cell to redraw.
+    pub fn load(&mut self, lines: &[String]) -> Result<Vec<CellChange>, String> {
+        let (rows, cols) = self.size();
+        let blank = vec![vec![G::BLANK; cols]; rows];
+        let (mut current, mut memory) = (blank.clone(), blank);
+        let mut x = HashMap::<(usize, usize), char>::default();
+        let (mut seed, mut rng) = (None, None);
+        let mut variables = self.grammar.variables.iter().cloned().collect::<HashMap<_, _>>();
+        for line in lines {
+            let malformed = || format!("malformed state `{}`", line);
+            let (name, rest) = line.split_once(' ').ok_or_else(malformed)?;
+            let mut it = rest.splitn(3, ' ');
+            let mut number = || it.next().and_then(|n| n.parse::<usize>().ok()).ok_or_else(malformed);
+            match name {
+                "seed" => seed = Some(rest.parse::<u64>().map_err(|_| malformed())?),
+                "rng" => rng = Some(rest.parse::<u64>().map_err(|_| malformed())?),
+                "size" => {}
+                "var" => {
+                    let (name, value) = rest.split_once(' ').ok_or_else(malformed)?;
+                    let value = value.parse::<i64>().map_err(|_| malformed())?;
+                    // variables no longer declared are dropped
+                    if let Some(variable) = variables.get_mut(name) {
+                        *variable = value;
+                    }
+                }
+                "x" => {
+                    let (row, col) = (number()?, number()?);
+                    let mut c = it.next().unwrap_or("").chars();
+                    let (Some(c), None) = (c.next(), c.next()) else {
+                        return Err(malformed());
+                    };
+                    if row >= rows || col >= cols {
+                        return Err(format!("saved game does not fit in {}x{}", rows, cols));
+                    }
+                    x.insert((row, col), c);
+                }
+                "current" | "memory" => {
+                    let (row, cells) = rest.split_once(' ').ok_or_else(malformed)?;
+                    let row = row.parse::<usize>().map_err(|_| malformed())?;
+                    let cells = G::decode(cells).ok_or_else(malformed)?;
+                    if row >= rows || cells.len() > cols {
+                        return Err(format!("saved game does not fit in {}x{}", rows, cols));
+                    }
+                    let grid = if name == "current" { &mut current } else { &mut memory };
+                    grid[row][..cells.len()].copy_from_slice(&cells);
                 }
+                _ => return Err(malformed()),
             }
-        ).collect()
+        }
+        let (Some(seed), Some(rng)) = (seed, rng) else {
+            return Err("saved game has no random generator state".to_string());
+        };
+        self.seed = seed;
+        self.rng = StdRng::seed_from_u64(rng);
+        self.current = current;
+        self.memory = memory;
+        self.x = x;
+        self.variables = variables;
+        self.undo.clear();
+        self.rematch();
+        Ok(self.cells())
+    }
+
+    /// Matches all rules anew, after the grammar or the whole grid changed.
+    fn rematch(&mut self) {
+        let reads = self.grammar.rules.values().flatten()
+            .flat_map(Self::rule_reads)
+            .chain(std::iter::once((0, 0)))
+            .collect::<BTreeSet<_>>();
+        self.reads = reads.into_iter().collect();
+        self.matches.clear();
+        let positions = self.x.keys().copied().collect::<Vec<_>>();
+        positions.into_iter().for_each(|position| self.match_at(position));
+    }
+
+    /// Matches the rules of the nonterminals whose rules read any of the cells changed.
+    fn rematch_cells(&mut self, cells: &[(usize, usize)]) {
+        let mut positions = BTreeSet::<(usize, usize)>::default();
+        for &(row, col) in cells {
+            for &(dr, dc) in self.reads.iter() {
+                let (r, c) = (row as i32 - dr, col as i32 - dc);
+                if r >= 0 && c >= 0 {
+                    positions.insert((r as usize, c as usize));
+                }
+            }
+        }
+        positions.into_iter().for_each(|position| self.match_at(position));
+    }
+
+    /// Updates the applicable rules of the nonterminal at a position, if any is there.
+    fn match_at(&mut self, position: (usize, usize)) {
+        for matches in self.matches.values_mut() {
+            let stale = matches.range((position, 0)..=(position, usize::MAX)).copied().collect::<Vec<_>>();
+            stale.iter().for_each(|m| { matches.remove(m); });
+        }
+        let Some(rules) = self.x.get(&position).and_then(|symbol| self.grammar.rules.get(symbol)) else {
+            return;
+        };
+        for (idx, rule) in rules.iter().enumerate() {
+            if self.is_rule_applicable(position.0 as i32 - rule.ro, position.1 as i32 - rule.co, rule) {
+                self.matches.entry(rule.key).or_default().insert((position, idx));
+            }
+        }
     }
     fn is_rule_applicable(&self, ro: i32, co: i32, rule: &Rule) -> bool {
         let mut r = ro;
@@ -167,10 +487,11 @@ impl Derivation {
         return true;
     }
 
-    fn apply_rule(&mut self, ro: i32, co: i32, rule: &Rule) -> Vec<TerminalEvent> {
+    fn apply_rule(&mut self, ro: i32, co: i32, rule: &Rule) -> Vec<CellChange> {
         let mut r: i32 = ro;
         let mut c: i32 = co;
-        let mut ret = Vec::<TerminalEvent>::default();
+        let mut ret = Vec::<CellChange>::default();
+        let mut touched = Vec::<(usize, usize)>::default();
         for p in rule.rhs_all.chars() {
             if p == '\n' {
                 r += 1;
@@ -219,14 +540,14 @@ impl Derivation {
                 if d.c == 255 as char {
                     d = G { c: ' ', fore: rule.fore, back, z_ord: 'a' as u8 };
                 }
-                let cidx = self.get_color(d.fore, d.back);
 
                 if rule.z_ord >= self.memory[r as usize][c as usize].z_ord {
-                    ret.push(TerminalEvent{
+                    ret.push(CellChange {
                         row: r as usize,
                         col: c as usize,
-                        s: d.c.to_string(),
-                        attr: cidx,
+                        c: d.c,
+                        fore: d.fore,
+                        back: d.back,
                     });
                     if !is_nonterminal {
                         //terminal symbol: save all
@@ -244,84 +565,226 @@ impl Derivation {
                 } else {
                     self.x.remove(&(r as usize, c as usize));
                 }
+                touched.push((r as usize, c as usize));
             }
             c+= 1;
         }
+        self.rematch_cells(&touched);
         return ret;
     }
 
-    pub fn step(&mut self, key: char) -> DerivationResult {
-        // choose random nonterminal instance and apply a single random rule
-        const MAGIC: char = '?';
-
-        // non-terminals to choose from: LHS's of rules given by key
-        let a = HashSet::from_iter(
-            self.grammar.rules.iter().map(|(_lhs, rules)| {
-                rules.iter()
-                    .filter(|&rule| {
-                        (rule.key == key) || (rule.key == MAGIC)
-                    })
-                    .map(|rule| rule.lhs.to_string())
-            }).flatten()
-        );
-
-        // positions of applicable rules
-        let xx = Vec::from_iter(
-            self.x.iter()
-                .filter(|(_position, nonterminal)| {
-                    a.contains(&nonterminal.to_string())
-                })
-                .map(|(position, _nonterminal)| {
-                    position.clone()
-                })
-        );
-
-        if xx.is_empty() {
-            return Default::default();
-        }
-
-        let mut applicable_rules = Vec::<(&(usize, usize), &Rule)>::default();
-        for position in xx.iter() {
-            if let Some(symbol) = self.x.get(position) {
-                self.grammar.rules.contains_key(symbol);
-                let symbol = self.x.get(&position).unwrap();
-                if let Some(rules) = self.grammar.rules.get(symbol) {
-                    for rule in rules.iter() {
-                        if (rule.key == key) || rule.key == MAGIC {
-                            if self.is_rule_applicable(position.0 as i32 - rule.ro, position.1 as i32 - rule.co, rule) {
-                                applicable_rules.push((position, rule));
-                            }
-                        }
-                    }
+    /// Cells written by a rule relative to the position of its nonterminal.
+    fn rule_writes(rule: &Rule) -> Vec<(i32, i32)> {
+        let horizontal = rule.cq > rule.co;
+        let mut writes = Vec::<(i32, i32)>::default();
+        for (r, row) in rule.rhs_all.split('\n').enumerate() {
+            let r = r as i32;
+            for (c, p) in row.chars().enumerate() {
+                let c = c as i32;
+                if p != ' ' && (if horizontal { c > rule.cm } else { r > rule.rm }) {
+                    writes.push((r - rule.rq, c - rule.cq));
                 }
             }
+        }
+        writes
+    }
+
+    /// Applicable rules for a key of the highest priority class among them, in a fixed order
+    /// for random choices to be reproducible. Synchronous keys keep the highest class of each
+    /// nonterminal instance, as every instance applies a rule of its own. Conditions on
+    /// variables are checked here as variables change without the grid changing.
+    fn candidates(&self, key: Key) -> Vec<((usize, usize), usize)> {
+        let mut candidates = self.matches.get(&key).into_iter().flatten()
+            .chain(self.matches.get(&MAGIC).filter(|_| key != MAGIC).into_iter().flatten())
+            .copied()
+            .filter(|&(position, idx)| self.conditions_hold(self.rule(position, idx)))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        let priority = |&(position, idx): &((usize, usize), usize)| self.rule(position, idx).priority;
+        let highest = |group: &[((usize, usize), usize)]| {
+            let max = group.iter().map(priority).max();
+            group.iter().filter(|candidate| Some(priority(candidate)) == max).copied().collect::<Vec<_>>()
         };
+        if self.grammar.synchronous.contains(&key) {
+            candidates.chunk_by(|a, b| a.0 == b.0).flat_map(highest).collect()
+        } else {
+            highest(&candidates)
+        }
+    }
+
+    /// Rules a key would choose from, with the positions of their nonterminals.
+    pub fn applicable(&self, key: Key) -> Vec<((usize, usize), Rule)> {
+        self.candidates(key).into_iter()
+            .map(|(position, idx)| (position, self.rule(position, idx).clone()))
+            .collect()
+    }
+
+    /// Cells on the grid a rule matches and writes when applied at the nonterminal at a position.
+    pub fn region(&self, position: (usize, usize), rule: &Rule) -> Vec<(usize, usize)> {
+        let cells = Self::rule_reads(rule).into_iter()
+            .chain(Self::rule_writes(rule))
+            .map(|(dr, dc)| (position.0 as i32 + dr, position.1 as i32 + dc))
+            .filter(|&(r, c)| r > 0 && r < self.rows && c >= 0 && c < self.cols)
+            .map(|(r, c)| (r as usize, c as usize))
+            .collect::<BTreeSet<_>>();
+        cells.into_iter().collect()
+    }
+
+    fn rule(&self, position: (usize, usize), idx: usize) -> &Rule {
+        &self.grammar.rules[&self.x[&position]][idx]
+    }
 
-        let weight_sum: f32 = applicable_rules.iter()
-            .map(|x| x.1.weight)
-            .map(|x| x as f32).sum();
-        let uniform_random = weight_sum * (rand::random::<u32>() as f32) / (u32::MAX as f32);
+    /// Index of a random item, chosen with probability proportional to its weight.
+    fn choose(&mut self, weights: &[i32]) -> Option<usize> {
+        let weight_sum: f32 = weights.iter().map(|&w| w as f32).sum();
+        let uniform_random = weight_sum * (self.rng.gen::<u32>() as f32) / (u32::MAX as f32);
 
         let mut weight_sum = 0.0;
-        let mut rule_chosen: Option<(&(usize, usize), usize)> = None;
-        for (idx, (position, rule)) in applicable_rules.iter().enumerate() {
-            weight_sum += rule.weight as f32;
-            if weight_sum >= uniform_random {
-                rule_chosen = Some((position, idx));
-                break;
-            }
+        weights.iter().position(|&w| {
+            weight_sum += w as f32;
+            weight_sum >= uniform_random
+        })
+    }
+
+    fn apply(&mut self, position: (usize, usize), rule: &Rule) -> Vec<CellChange> {
+        self.apply_rule(position.0 as i32 - rule.rq, position.1 as i32 - rule.cq, rule)
+    }
+
+    pub fn step(&mut self, key: Key) -> DerivationResult {
+        if self.grammar.synchronous.contains(&key) {
+            return self.step_synchronous(key);
         }
-        if let Some(((row, col), idx)) = rule_chosen {
-            let rule = applicable_rules[idx].1.clone();
+        // choose random nonterminal instance and apply a single random rule
+        let candidates = self.candidates(key);
+        if candidates.is_empty() {
+            return Default::default();
+        }
+        let weights = candidates.iter()
+            .map(|&(position, idx)| self.rule(position, idx).weight)
+            .collect::<Vec<_>>();
+        if let Some(chosen) = self.choose(&weights) {
+            let (position, idx) = candidates[chosen];
+            let rule = self.rule(position, idx).clone();
+            self.update_variables(&rule);
             DerivationResult {
-                terminal_events: self.apply_rule(*row as i32 - rule.rq, *col as i32 - rule.cq, &rule),
+                changes: self.apply(position, &rule),
                 score_delta: rule.reward,
-                errors_delta: 0,
+                errors_delta: rule.errors,
                 dbg_rule: rule.lhs_all.clone(),
                 sound_alias: rule.sound,
+                end: rule.end.clone(),
+                applied: vec![(position, rule)],
             }
         } else {
             Default::default()
         }
     }
+
+    /// Applies a random rule at every nonterminal instance at once. All rules are matched
+    /// against the grid as it was before the step; of instances writing the same cell, only
+    /// the first one in random order is applied.
+    fn step_synchronous(&mut self, key: Key) -> DerivationResult {
+        let candidates = self.candidates(key);
+        let mut chosen = Vec::<((usize, usize), Rule)>::default();
+        for instance in candidates.chunk_by(|a, b| a.0 == b.0) {
+            let weights = instance.iter()
+                .map(|&(position, idx)| self.rule(position, idx).weight)
+                .collect::<Vec<_>>();
+            if let Some(i) = self.choose(&weights) {
+                let (position, idx) = instance[i];
+                chosen.push((position, self.rule(position, idx).clone()));
+            }
+        }
+        chosen.shuffle(&mut self.rng);
+
+        let mut written = HashSet::<(i32, i32)>::default();
+        let mut result = DerivationResult::default();
+        for (position, rule) in chosen {
+            let cells = Self::rule_writes(&rule).into_iter()
+                .map(|(dr, dc)| (position.0 as i32 + dr, position.1 as i32 + dc))
+                .collect::<Vec<_>>();
+            if cells.iter().any(|cell| written.contains(cell)) {
+                continue;
+            }
+            written.extend(cells);
+            result.changes.extend(self.apply(position, &rule));
+            self.update_variables(&rule);
+            result.score_delta += rule.reward;
+            result.errors_delta += rule.errors;
+            if result.end.is_none() {
+                result.end = rule.end.clone();
+            }
+            // navigation wins over other rules applied
+            if result.sound_alias != '>' {
+                result.dbg_rule = rule.lhs_all.clone();
+                result.sound_alias = rule.sound;
+            }
+            result.applied.push((position, rule));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use super::*;
+
+    // box-drawing characters work as nonterminal, replacement and sound alias of a header
+    #[test]
+    fn multibyte_header_steps() {
+        let grammar = Grammar2D::load_from("box.cfg", "^╬\n=╔╬d═\n@~@@╬\n".as_bytes(), Default::default())
+            .unwrap_or_else(|error| panic!("{}", error));
+        assert_eq!(grammar.rules[&'╬'][0].sound, '╔');
+        let mut derivation = Derivation::new(grammar, 5, 9, 7);
+        derivation.start();
+        let find = |derivation: &Derivation| (0..5).flat_map(|r| (0..9).map(move |c| (r, c)))
+            .find(|&(r, c)| derivation.cell(r, c).0 == '╬').expect("no nonterminal on the grid");
+        let (row, col) = find(&derivation);
+        derivation.step(Key::Char('d'));
+        derivation.step(Key::Char('d'));
+        assert_eq!(find(&derivation), (row, col + 2));
+        assert_eq!((derivation.cell(row, col).0, derivation.cell(row, col + 1).0), ('═', '═'));
+    }
+
+    // a higher priority rule of one instance does not keep other instances from applying theirs
+    #[test]
+    fn synchronous_priority_per_instance() {
+        let program = "#*T\n^Aul\n^Bur\n==ATa\n@@@\n==BTb\n@@@\n==BTc priority=1\n@@@\n";
+        let grammar = Grammar2D::load_from("sync.cfg", program.as_bytes(), Default::default())
+            .unwrap_or_else(|error| panic!("{}", error));
+        let mut derivation = Derivation::new(grammar, 5, 9, 7);
+        derivation.start();
+        let result = derivation.step(Key::Char('T'));
+        let mut written = result.changes.iter().map(|change| change.c).collect::<Vec<_>>();
+        written.sort();
+        assert_eq!(written, ['a', 'c']);
+    }
+
+    // the rules kept applicable while stepping are those a full match of the state finds
+    #[test]
+    fn incremental_matches_equal_rescan() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/programs");
+        for program in ["life.cfg", "snake.cfg", "sokoban.cfg", "tetris.cfg", "arkanoid.cfg", "flowers.cfg"] {
+            let filename = dir.join(program).to_string_lossy().to_string();
+            let grammar = Grammar2D::load(&filename).unwrap_or_else(|error| panic!("{}", error));
+            let mut keys = grammar.rules.values().flatten().map(|rule| rule.key).collect::<Vec<_>>();
+            keys.sort_by_key(|key| key.to_string());
+            keys.dedup();
+            let mut derivation = Derivation::new(grammar.clone(), 30, 80, 7);
+            derivation.start();
+            for step in 0..1000 {
+                derivation.step(keys[step % keys.len()]);
+                if step % 10 != 0 {
+                    continue;
+                }
+                let mut rescanned = Derivation::new(grammar.clone(), 30, 80, 7);
+                rescanned.load(&derivation.save()).unwrap();
+                for &key in keys.iter() {
+                    assert_eq!(derivation.candidates(key), rescanned.candidates(key),
+                               "{} after step {} with key {}", program, step, key);
+                }
+            }
+        }
+    }
 }
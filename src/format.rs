@@ -0,0 +1,88 @@
+use crate::grammar::Grammar2D;
+
+// rules compared by a round trip: header and right-hand side, generated variants included
+fn rule_set(grammar: &Grammar2D) -> Vec<(String, Vec<String>)> {
+    let mut rules = grammar.rules.values().flatten()
+        .map(|rule| (rule.lhs_all.clone(), rule.written_rhs()))
+        .collect::<Vec<_>>();
+    rules.sort();
+    rules
+}
+
+/// Writes the program out and reads it back; describes the first difference found.
+pub fn check(filename: &str, grammar: &Grammar2D) -> Result<(), String> {
+    let written = grammar.to_string();
+    let reloaded = Grammar2D::load_from(filename, written.as_bytes(), Default::default())
+        .map_err(|error| format!("formatted program does not load:\n{}", error))?;
+    if rule_set(&reloaded) != rule_set(grammar) {
+        return Err("formatted program has different rules".to_string());
+    }
+    if reloaded.to_string() != written {
+        return Err("formatted program is not stable".to_string());
+    }
+    Ok(())
+}
+
+/// Entry point of `zero fmt [--check] <program.cfg>...`; prints the canonical form of the
+/// programs, or with `--check` only verifies that they survive it. Returns the exit code.
+pub fn run(args: &[String]) -> i32 {
+    let check_only = args.first().is_some_and(|arg| arg == "--check");
+    let filenames = if check_only { &args[1..] } else { args };
+    if filenames.is_empty() {
+        eprintln!("usage: zero fmt [--check] <program.cfg>...");
+        return 2;
+    }
+    let mut failed = false;
+    for filename in filenames {
+        let grammar = match Grammar2D::load(filename) {
+            Ok(grammar) => grammar,
+            Err(error) => {
+                eprint!("{}", error);
+                failed = true;
+                continue;
+            }
+        };
+        if check_only {
+            if let Err(message) = check(filename, &grammar) {
+                println!("{}: {}", filename, message);
+                failed = true;
+            }
+        } else {
+            print!("{}", grammar);
+        }
+    }
+    if failed { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use super::*;
+
+    fn program_files(dir: &Path) -> Vec<PathBuf> {
+        let mut programs = Vec::<PathBuf>::default();
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                programs.extend(program_files(&path));
+            } else if path.extension().is_some_and(|extension| extension == "cfg") {
+                programs.push(path);
+            }
+        }
+        programs
+    }
+
+    #[test]
+    fn shipped_programs_round_trip() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("assets/programs");
+        let programs = program_files(&dir);
+        assert!(!programs.is_empty());
+        for program in programs {
+            let filename = program.to_string_lossy();
+            let grammar = Grammar2D::load(&filename).unwrap_or_else(|error| panic!("{}", error));
+            if let Err(message) = check(&filename, &grammar) {
+                panic!("{}: {}", filename, message);
+            }
+        }
+    }
+}
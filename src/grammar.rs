@@ -12,33 +12,366 @@ pub struct Rule {
     pub fore: u8,
     pub back: u8,
     pub reward: i32,
-    pub key: char,
+    /// Errors counted when the rule is applied (`error=`).
+    pub errors: i32,
+    pub key: Key,
     pub ctx: char,
     pub rep: char,
     pub ctx_rep: char,
     pub weight: i32,
+    pub priority: i32,
     pub z_ord: u8,
     pub sound: char,
+    pub source: usize,
+    pub line: usize,
+    /// Conditions on variables that must all hold for the rule to apply (`if=`).
+    pub conditions: Vec<Condition>,
+    /// Changes of variables when the rule is applied (`add=` and `set=`).
+    pub effects: Vec<Effect>,
+    /// Outcome of the game when the rule is applied (`end=`, `text=` and `next=`).
+    pub end: Option<GameEnd>,
 }
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GameResult {
+    Win,
+    Lose,
+    Level,
+}
+
+/// Outcome of a game declared by a rule, with a message and the program to go on with.
+#[derive(Clone, Debug)]
+pub struct GameEnd {
+    pub result: GameResult,
+    pub text: Option<String>,
+    pub next: Option<String>,
+}
+
+/// Comparison of a variable with a number, e.g. `lives>0`.
+#[derive(Clone, Debug)]
+pub struct Condition {
+    pub variable: String,
+    pub comparison: Comparison,
+    pub value: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessEqual,
+    Equal,
+    NotEqual,
+    GreaterEqual,
+    Greater,
+}
+
+/// Variables of the game a condition may test without declaring them; only rewards change them.
+pub const REWARD_VARIABLES: [&str; 3] = ["score", "time", "errors"];
+
+// operators of a condition, two character ones first as they start like others
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("<=", Comparison::LessEqual),
+    (">=", Comparison::GreaterEqual),
+    ("!=", Comparison::NotEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+    ("=", Comparison::Equal),
+];
+
+impl Condition {
+    /// Parses `name<op>number` with `op` one of `<`, `<=`, `=`, `!=`, `>=` and `>`.
+    fn parse(condition: &str) -> Option<Condition> {
+        let (i, op, comparison) = COMPARISONS.iter()
+            .filter_map(|&(op, comparison)| condition.find(op).map(|i| (i, op, comparison)))
+            .min_by_key(|&(i, op, _)| (i, usize::MAX - op.len()))?;
+        Some(Condition {
+            variable: Grammar2D::variable_name(&condition[..i])?,
+            comparison,
+            value: condition[i + op.len()..].parse().ok()?,
+        })
+    }
+
+    pub fn holds(&self, value: i64) -> bool {
+        match self.comparison {
+            Comparison::Less => value < self.value,
+            Comparison::LessEqual => value <= self.value,
+            Comparison::Equal => value == self.value,
+            Comparison::NotEqual => value != self.value,
+            Comparison::GreaterEqual => value >= self.value,
+            Comparison::Greater => value > self.value,
+        }
+    }
+}
+
+/// Change of a variable by a rule applied: `value` is added to it, or it is set to `value`.
+#[derive(Clone, Debug)]
+pub struct Effect {
+    pub variable: String,
+    pub set: bool,
+    pub value: i64,
+}
+
+impl Effect {
+    /// Parses `name:number`.
+    fn parse(effect: &str, set: bool) -> Option<Effect> {
+        let (name, value) = effect.split_once(':')?;
+        Some(Effect { variable: Grammar2D::variable_name(name)?, set, value: value.parse().ok()? })
+    }
+
+    pub fn apply(&self, value: i64) -> i64 {
+        if self.set { self.value } else { value.saturating_add(self.value) }
+    }
+}
+
+impl Rule {
+    /// Right-hand side as written by the serializer: rows without trailing blanks and without
+    /// trailing empty rows, a symbol starting a row is written as `*` again.
+    pub fn written_rhs(&self) -> Vec<String> {
+        let mut rows = self.rhs_all.split('\n')
+            .map(|row| match row.strip_prefix(['#', '=', '^']) {
+                Some(rest) if row.starts_with(self.lhs) => format!("*{}", rest),
+                _ => row.to_string(),
+            }.trim_end_matches(' ').to_string())
+            .collect::<Vec<_>>();
+        while rows.last().is_some_and(|row| row.is_empty()) {
+            rows.pop();
+        }
+        rows
+    }
+}
+
+#[derive(Default, Clone)]
 pub struct Grammar2D {
     pub seeds: Vec<Start>,
     pub rules: HashMap<char, Vec<Rule>>,
     pub nonterminals: HashSet<char>,
     pub help: String,
     pub sounds: HashMap<char, String>,
+    pub sources: Vec<String>,
+    /// Keys on which rules apply at all nonterminal instances at once (`#*K`).
+    pub synchronous: Vec<Key>,
+    /// Key undoing player steps and how many of them can be undone (`#^K N`).
+    pub undo: Option<(Key, usize)>,
+    /// Named integer variables with their initial values (`#$name N`), in declaration order.
+    pub variables: Vec<(String, i64)>,
+    /// Errors ending the game when reached (`#-N`).
+    pub error_limit: Option<i64>,
+    // content of included files read in advance, by canonical path, while loading
+    files: HashMap<PathBuf, Vec<u8>>,
 }
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use bevy::utils::HashSet;
+use std::path::{Path, PathBuf};
+use flate2::bufread::GzDecoder;
+
+/// Key triggering a rule, either a character or a named special key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    Enter,
+    Escape,
+    Backspace,
+    Function(u8),
+}
+
+impl Key {
+    /// Parses a key name of a rule attribute: a single character, `up`, `down`, `left`,
+    /// `right`, `enter`, `escape`, `backspace` or `f1` to `f12`.
+    pub fn from_name(name: &str) -> Option<Key> {
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Some(Key::Char(c));
+        }
+        let key = match name {
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "enter" => Key::Enter,
+            "escape" => Key::Escape,
+            "backspace" => Key::Backspace,
+            _ => match name.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                Some(n) if (1..=12).contains(&n) => Key::Function(n),
+                _ => return None,
+            },
+        };
+        Some(key)
+    }
+
+    /// Parses a list of keys: a single key name (`right`), comma separated names
+    /// (`down,right,up`) or else one character each (`sdw`); `.` stands for no key.
+    pub fn from_names(keys: &str) -> Result<Vec<Option<Key>>, String> {
+        let names = if keys.contains(',') || Key::from_name(keys).is_some() {
+            keys.split(',').map(String::from).collect::<Vec<_>>()
+        } else {
+            keys.chars().map(String::from).collect::<Vec<_>>()
+        };
+        names.iter().map(|name| match name.as_str() {
+            "." => Ok(None),
+            _ => Key::from_name(name).map(Some).ok_or_else(|| format!("unknown key `{}`", name)),
+        }).collect()
+    }
+}
+
+/// Writes a key the way `Key::from_name` reads it.
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Char(c) => write!(f, "{}", c),
+            Key::Up => write!(f, "up"),
+            Key::Down => write!(f, "down"),
+            Key::Left => write!(f, "left"),
+            Key::Right => write!(f, "right"),
+            Key::Enter => write!(f, "enter"),
+            Key::Escape => write!(f, "escape"),
+            Key::Backspace => write!(f, "backspace"),
+            Key::Function(n) => write!(f, "f{}", n),
+        }
+    }
+}
 
+#[derive(Clone)]
 pub struct Start {
     pub ul: char,
     pub lr: char,
     pub c: char,
+    pub source: usize,
+    pub line: usize,
+}
+
+/// Single problem found while loading a grammar, located by 1-based line and column.
+#[derive(Clone, Debug)]
+pub struct Problem {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(f, "{}:{}:{}: {}", self.file, self.line, self.column, self.message)
+        }
+    }
+}
+
+/// All problems found in a grammar file; the grammar is not usable if any was found.
+#[derive(Clone, Debug, Default)]
+pub struct GrammarError {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for GrammarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for problem in self.problems.iter() {
+            writeln!(f, "{}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for GrammarError {}
+
+/// Writes the grammar back as a single program: help, sound aliases, seeds and then the rules
+/// in source order, with included files inlined. Generated variants are left out as their
+/// attributes regenerate them. Comments are not kept, so the output does not replace the source.
+impl fmt::Display for Grammar2D {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.help.is_empty() {
+            writeln!(f, "#!{}", self.help)?;
+        }
+        let mut sounds = self.sounds.iter().collect::<Vec<_>>();
+        sounds.sort();
+        for (alias, sound_file) in sounds {
+            writeln!(f, "#={}{}", alias, sound_file)?;
+        }
+        for key in self.synchronous.iter() {
+            writeln!(f, "#*{}", key)?;
+        }
+        if let Some((key, depth)) = self.undo {
+            writeln!(f, "#^{} {}", key, depth)?;
+        }
+        for (name, value) in self.variables.iter() {
+            writeln!(f, "#${} {}", name, value)?;
+        }
+        if let Some(limit) = self.error_limit {
+            writeln!(f, "#-{}", limit)?;
+        }
+        for seed in self.seeds.iter() {
+            writeln!(f, "^{}{}{}", seed.c, seed.ul, seed.lr)?;
+        }
+        let rules = self.written_rules();
+        for (i, rule) in rules.iter().enumerate() {
+            writeln!(f, "={}", rule.lhs_all)?;
+            let rhs = rule.written_rhs();
+            // consecutive headers with the same right-hand side share it
+            if rules.get(i + 1).is_some_and(|next| next.written_rhs() == rhs) {
+                continue;
+            }
+            for row in rhs.iter() {
+                writeln!(f, "{}", row)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// sound, symbol, key and replacement are mandatory in a rule header
+const MIN_HEADER_LEN: usize = 5;
+
+// characters of a rule header after the `=` read by column, the positional reward and
+// weight follow them
+const FIXED_COLUMNS: usize = 10;
+
+// attribute values of a rule header with the columns of their words, by name
+type Attributes<'a> = HashMap<&'a str, (usize, &'a str)>;
+
+// names of the `name=value` attributes following the fixed-column part of a rule header
+const ATTRIBUTES: [&str; 20] = [
+    "sound", "key", "fore", "back", "ctx", "ctxrep", "z", "reward", "error", "weight",
+    "priority", "rot", "flip", "cell", "if", "add", "set", "end", "text", "next",
+];
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Existing program file for `path`, falling back to its compressed `.gz` sibling.
+pub fn resolve_program(path: &Path) -> Option<PathBuf> {
+    if path.exists() {
+        return Some(path.to_path_buf());
+    }
+    let mut gz = path.as_os_str().to_os_string();
+    gz.push(".gz");
+    let gz = PathBuf::from(gz);
+    if gz.exists() { Some(gz) } else { None }
+}
+
+/// Existing program file `name` in the directory of another program, as navigation targets
+/// and `next=` programs are found.
+pub fn sibling_program(program: &str, name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::from(program);
+    path.pop();
+    path.push(name);
+    resolve_program(&path)
+}
+
+/// Program text, decompressed transparently if it is gzipped.
+fn decompress<'a>(mut f: impl BufRead + 'a) -> std::io::Result<Box<dyn BufRead + 'a>> {
+    if f.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(f))))
+    } else {
+        Ok(Box::new(f))
+    }
 }
 
 impl Grammar2D {
@@ -51,12 +384,46 @@ impl Grammar2D {
         }
     }
 
-    fn _process(&mut self, lhss: &Vec<String>, rule: &Vec<String>) -> bool {
+    fn _process(&mut self, source: usize, lhss: &[(usize, String)], rule: &[String],
+                problems: &mut Vec<Problem>) -> bool {
         let rhs = rule.join("\n");
-        lhss.iter().for_each(|lhs| self.add_rule(&lhs, &rhs));
+        for (line, lhs) in lhss.iter() {
+            if let Err((column, message)) = self.add_rule(lhs, &rhs, source, *line) {
+                problems.push(Problem {
+                    file: self.sources[source].clone(),
+                    line: *line,
+                    column,
+                    message,
+                });
+            }
+        }
         true
     }
 
+    // length of the fixed-column part of a rule header, attributes left out
+    fn fixed_len(header: &str) -> usize {
+        Self::split_attributes(header).0.chars().count()
+    }
+
+    fn missing_character(header: &str) -> String {
+        let missing = ["sound", "symbol", "key", "replacement"][Self::fixed_len(header).clamp(1, 4) - 1];
+        format!("rule header `{}` is missing its {} character", header, missing)
+    }
+
+    fn check_header(file: &str, line_no: usize, line: &str) -> Option<Problem> {
+        let len = Self::fixed_len(line);
+        if len < MIN_HEADER_LEN {
+            Some(Problem {
+                file: file.to_string(),
+                line: line_no,
+                column: len + 1,
+                message: Self::missing_character(line),
+            })
+        } else {
+            None
+        }
+    }
+
     fn origin(_s: char, rhs: &str, spec: char, mut ord: i32) -> (i32, i32) {
         let mut row_off = 0;
         let mut col_off = 0;
@@ -76,10 +443,159 @@ impl Grammar2D {
         (-1, -1)
     }
 
-    fn add_rule(&mut self, lhs0: &str, rhs: &str) {
-        let s = lhs0.chars().nth(2).unwrap();
-        let sound = lhs0.chars().nth(1).unwrap();
-        let lhs = &lhs0[1..(lhs0.chars().count())];
+    /// Splits a rule header into its fixed-column part and the words of the `name=value`
+    /// attributes following it with their columns, e.g. `==gaK rot=sdw` into `==gaK` and
+    /// `rot=sdw` at column 7. Within the fixed columns an attribute name is lowercase, after
+    /// them any word with a `=` starts the attributes.
+    fn split_attributes(lhs0: &str) -> (&str, Vec<(usize, &str)>) {
+        let start = lhs0.match_indices(' ').map(|(i, _)| i).find(|&i| {
+            let word = lhs0[i + 1..].split(' ').next().unwrap_or("");
+            match word.split_once('=') {
+                _ if lhs0[..=i].chars().count() > FIXED_COLUMNS => word.contains('='),
+                Some((name, _)) => !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase()),
+                None => false,
+            }
+        });
+        let Some(start) = start else {
+            return (lhs0, vec![]);
+        };
+        let mut words = Vec::<(usize, &str)>::default();
+        let mut column = lhs0[..=start].chars().count() + 1;
+        for word in lhs0[start + 1..].split(' ') {
+            if !word.is_empty() {
+                words.push((column, word));
+            }
+            column += word.chars().count() + 1;
+        }
+        (&lhs0[..start], words)
+    }
+
+    /// Attribute values with their columns by name; unknown, repeated or malformed attributes
+    /// are errors.
+    fn parse_attributes<'a>(words: &[(usize, &'a str)]) -> Result<Attributes<'a>, (usize, String)> {
+        let mut attributes = Attributes::default();
+        for &(column, word) in words {
+            let (name, value) = match word.split_once('=') {
+                Some((name, value)) if !value.is_empty() => (name, value),
+                _ => return Err((column, format!("malformed attribute `{}`, expected `name=value`", word))),
+            };
+            if !ATTRIBUTES.contains(&name) {
+                return Err((column, format!("unknown attribute `{}`", name)));
+            }
+            if attributes.insert(name, (column, value)).is_some() {
+                return Err((column, format!("attribute `{}` is given twice", name)));
+            }
+        }
+        Ok(attributes)
+    }
+
+    fn char_attribute(attributes: &Attributes, name: &str) -> Result<Option<char>, (usize, String)> {
+        match attributes.get(name) {
+            Some(&(column, value)) => {
+                let mut chars = value.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Ok(Some(c)),
+                    _ => Err((column, format!("attribute `{}` must be a single character, not `{}`", name, value))),
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn number_attribute<T: std::str::FromStr>(attributes: &Attributes, name: &str,
+                                              valid: impl Fn(&T) -> bool) -> Result<Option<T>, (usize, String)> {
+        match attributes.get(name) {
+            Some(&(column, value)) => match value.parse::<T>() {
+                Ok(n) if valid(&n) => Ok(Some(n)),
+                _ => Err((column, format!("invalid value `{}` of attribute `{}`", value, name))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Name of a variable: lowercase letters, digits and `_`, starting with a letter.
+    fn variable_name(name: &str) -> Option<String> {
+        let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+            && name.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        valid.then(|| name.to_string())
+    }
+
+    /// Comma separated list of conditions or effects of an attribute.
+    fn list_attribute<T>(attributes: &Attributes, name: &str,
+                         parse: impl Fn(&str) -> Option<T>) -> Result<Vec<T>, (usize, String)> {
+        attributes.get(name).map_or(Ok(vec![]), |&(column, value)| value.split(',')
+            .map(|item| parse(item).ok_or_else(|| (column, format!("invalid `{}` of attribute `{}`", item, name))))
+            .collect())
+    }
+
+    /// Keys of the variants of a `rot` or `flip` attribute, at most `max` of them.
+    fn variant_keys(attributes: &Attributes, name: &str, max: usize) -> Result<Vec<Option<Key>>, (usize, String)> {
+        let Some(&(column, keys)) = attributes.get(name) else {
+            return Ok(vec![]);
+        };
+        let keys = Key::from_names(keys).map_err(|message| (column, message))?;
+        if keys.len() > max {
+            return Err((column, format!("attribute `{}` takes at most {} key(s), not {}", name, max, keys.len())));
+        }
+        Ok(keys)
+    }
+
+    /// Game outcome of `end=win|lose|level`, with the message of `text=` (`_` standing for a
+    /// space) and the program of `next=`.
+    fn end_attribute(attributes: &Attributes) -> Result<Option<GameEnd>, (usize, String)> {
+        let result = match attributes.get("end") {
+            Some((_, "win")) => GameResult::Win,
+            Some((_, "lose")) => GameResult::Lose,
+            Some((_, "level")) => GameResult::Level,
+            Some(&(column, value)) => return Err((column,
+                format!("invalid value `{}` of attribute `end`, expected win, lose or level", value))),
+            None => match attributes.get("text").or(attributes.get("next")) {
+                Some(&(column, _)) =>
+                    return Err((column, "attributes `text` and `next` need an `end` attribute".to_string())),
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(GameEnd {
+            result,
+            text: attributes.get("text").map(|(_, text)| text.replace('_', " ")),
+            next: attributes.get("next").map(|(_, next)| next.to_string()),
+        }))
+    }
+
+    /// Rules written in the program, in source order; variants generated from one share its
+    /// source and line and follow it.
+    pub fn written_rules(&self) -> Vec<&Rule> {
+        let mut rules = self.rules.values().flatten().collect::<Vec<_>>();
+        rules.sort_by_key(|rule| (rule.source, rule.line));
+        rules.dedup_by_key(|rule| (rule.source, rule.line));
+        rules
+    }
+
+    /// Whether a derivation running on this grammar may continue with the rules of `other`
+    /// instead of starting over: both must place the same seeds.
+    pub fn is_compatible(&self, other: &Grammar2D) -> bool {
+        self.seeds.len() == other.seeds.len()
+            && self.seeds.iter().zip(other.seeds.iter())
+                .all(|(a, b)| (a.c, a.ul, a.lr) == (b.c, b.ul, b.lr))
+    }
+
+    /// Program named by a navigation (`=>`) rule header: its last word before any attributes.
+    pub fn navigation_target(lhs_all: &str) -> &str {
+        Self::split_attributes(lhs_all).0.split(' ').next_back().unwrap_or("")
+    }
+
+    /// Adds the rule of a header and its variants; an error is located by its column.
+    fn add_rule(&mut self, header: &str, rhs: &str, source: usize, line: usize) -> Result<(), (usize, String)> {
+        let (lhs0, words) = Self::split_attributes(header);
+        let mut fixed = lhs0.chars().skip(1);
+        let (Some(header_sound), Some(s), Some(header_key), Some(rep)) =
+            (fixed.next(), fixed.next(), fixed.next(), fixed.next()) else {
+            return Err((Self::fixed_len(header) + 1, Self::missing_character(header)));
+        };
+        let attributes = Self::parse_attributes(&words)?;
+        let sound = Self::char_attribute(&attributes, "sound")?.unwrap_or(header_sound);
+        // the header starts with a single byte `=`, everything else is indexed by characters
+        let lhs = &lhs0[1..];
         if !self.rules.contains_key(&s) {
             self.rules.insert(s, vec![]);
             self.nonterminals.insert(s);
@@ -92,7 +608,8 @@ impl Grammar2D {
 
         let default = (0, 1);
         let (reward, weight) = if lhs.chars().count() > 10 {
-            let mut it = lhs[10..].split(' ');
+            let tail = String::from_iter(lhs.chars().skip(10));
+            let mut it = tail.split(' ');
             let some_reward = if let Some(a) = it.next() {
                 a.parse::<i32>().or::<i32>(Ok(0)).unwrap()
             } else {
@@ -107,86 +624,480 @@ impl Grammar2D {
         } else {
             default
         };
+        let reward = Self::number_attribute(&attributes, "reward", |_| true)?.unwrap_or(reward);
+        let weight = Self::number_attribute(&attributes, "weight", |&w| w >= 0)?.unwrap_or(weight);
 
-        let ctx_0 = Self::at_with_default(lhs, 6, 255 as char);
+        let ctx_0 = Self::char_attribute(&attributes, "ctx")?
+            .unwrap_or(Self::at_with_default(lhs, 6, 255 as char));
         let ctx = match ctx_0 {
             '?' => 255 as char,
             _ => ctx_0
         };
-        let key = lhs.chars().nth(2).unwrap();
-        let rep = lhs.chars().nth(3).unwrap();
-        let ctx_rep_0 = Self::at_with_default(lhs, 7, ' ');
+        let key = match attributes.get("key") {
+            Some(&(column, name)) => Key::from_name(name)
+                .ok_or_else(|| (column, format!("unknown key `{}`", name)))?,
+            None => Key::Char(header_key),
+        };
+        let ctx_rep_0 = Self::char_attribute(&attributes, "ctxrep")?
+            .unwrap_or(Self::at_with_default(lhs, 7, ' '));
         let ctx_rep  = match ctx_rep_0 {
             '*' => s,
             _ => ctx_rep_0
         };
 
-        let lhs_all = String::from(lhs);
+        let z_ord = match attributes.get("z") {
+            Some(&(column, value)) if !value.is_ascii() =>
+                return Err((column, format!("attribute `z` must be an ASCII character, not `{}`", value))),
+            Some(_) => Self::char_attribute(&attributes, "z")?.unwrap_or('a') as u8,
+            None => match Self::at_with_default(lhs, 8, 'a') {
+                z if z.is_ascii() => z as u8,
+                // the z order is the tenth column of the header
+                z => return Err((10, format!("z order must be an ASCII character, not `{}`", z))),
+            },
+        };
+
+        let lhs_all = String::from(&header[1..]);
         let rhs_all = str::replace(rhs, "*", &s.to_string());
 
         let rule = Rule {
             lhs: s, lhs_all, rhs_all,
             ro, co, rm, cm, rq, cq,
-            fore: Self::at_with_default(lhs, 4, '7')
-                .to_digit(10).unwrap_or(7).try_into().unwrap_or(7),
-            back: Self::at_with_default(lhs, 5, '8')
-                .to_digit(10).unwrap_or(0).try_into().unwrap_or(0),
-            reward, key, ctx,
+            fore: Self::number_attribute(&attributes, "fore", |&c: &u8| c <= 9)?
+                .unwrap_or(Self::at_with_default(lhs, 4, '7')
+                    .to_digit(10).unwrap_or(7).try_into().unwrap_or(7)),
+            back: Self::number_attribute(&attributes, "back", |&c: &u8| c <= 9)?
+                .unwrap_or(Self::at_with_default(lhs, 5, '8')
+                    .to_digit(10).unwrap_or(0).try_into().unwrap_or(0)),
+            reward,
+            errors: Self::number_attribute(&attributes, "error", |_| true)?.unwrap_or(0),
+            key, ctx,
             rep,
             ctx_rep,
             weight,
-            z_ord: Self::at_with_default(lhs, 8, 'a')  as u8,
+            priority: Self::number_attribute(&attributes, "priority", |_| true)?.unwrap_or(0),
+            z_ord,
             sound,
+            source,
+            line,
+            conditions: Self::list_attribute(&attributes, "if", Condition::parse)?,
+            effects: Self::list_attribute(&attributes, "add", |effect| Effect::parse(effect, false))?
+                .into_iter()
+                .chain(Self::list_attribute(&attributes, "set", |effect| Effect::parse(effect, true))?)
+                .collect(),
+            end: Self::end_attribute(&attributes)?,
+        };
+
+        let mut variants = Vec::<Rule>::default();
+        if rule.rq >= 0 {
+            let cell = Self::number_attribute(&attributes, "cell", |&w: &i32| w > 0)?.unwrap_or(1);
+            for (turns, key) in Self::variant_keys(&attributes, "rot", 3)?.into_iter().enumerate() {
+                if let Some(key) = key {
+                    variants.push(Self::transform(&rule, turns as u8 + 1, false, cell, key));
+                }
+            }
+            if let Some(Some(key)) = Self::variant_keys(&attributes, "flip", 1)?.first() {
+                variants.push(Self::transform(&rule, 0, true, cell, *key));
+            }
+        }
+        let rules = self.rules.get_mut(&s).unwrap();
+        rules.push(rule);
+        rules.append(&mut variants);
+        Ok(())
+    }
+
+    /// Copy of a rule with its pattern mirrored left to right (if `flip`) and then turned
+    /// clockwise by `turns` quarters. Columns are grouped into cells `cell` characters wide,
+    /// counted from the leftmost column of the pattern, and each cell is moved as a whole.
+    fn transform(rule: &Rule, turns: u8, flip: bool, cell: i32, key: Key) -> Rule {
+        // split the pattern into matched and replaced characters relative to the nonterminal
+        let horizontal = rule.cq > rule.co;
+        let mut matched = Vec::<(i32, i32, char)>::default();
+        let mut replaced = Vec::<(i32, i32, char)>::default();
+        for (r, row) in rule.rhs_all.split('\n').enumerate() {
+            for (c, p) in row.chars().enumerate() {
+                let (r, c) = (r as i32, c as i32);
+                let (lhs_side, rhs_side) = if horizontal {
+                    (c < rule.cm, c > rule.cm)
+                } else {
+                    (r < rule.rm, r > rule.rm)
+                };
+                if p == ' ' {
+                    continue;
+                }
+                // anchors other than the origins are replaced by what they stand for
+                if lhs_side && (r - rule.ro, c - rule.co) != (0, 0) {
+                    matched.push((r - rule.ro, c - rule.co, if p == '@' { rule.lhs } else { p }));
+                } else if rhs_side && (r - rule.rq, c - rule.cq) != (0, 0) {
+                    replaced.push((r - rule.rq, c - rule.cq, if p == '@' { rule.rep } else { p }));
+                }
+            }
+        }
+
+        let left = matched.iter().chain(replaced.iter())
+            .map(|&(_, c, _)| c)
+            .min().unwrap_or(0).min(0);
+        let left = left + (-left).div_euclid(cell) * cell;
+        let move_cell = |(r, c, p): (i32, i32, char)| {
+            let (mut r, mut x, sub) = (r, (c - left).div_euclid(cell), (c - left).rem_euclid(cell));
+            if flip {
+                x = -x;
+            }
+            for _ in 0..turns {
+                (r, x) = (x, -r);
+            }
+            (r, left + x * cell + sub, p)
+        };
+        let matched = matched.into_iter().map(move_cell).collect::<Vec<_>>();
+        let replaced = replaced.into_iter().map(move_cell).collect::<Vec<_>>();
+
+        // lay the pattern out again as @ LHS @ >>RHS<< with all anchors on the nonterminal row
+        let all = || matched.iter().chain(replaced.iter());
+        let top = all().map(|&(r, _, _)| r).min().unwrap_or(0).min(0);
+        let bottom = all().map(|&(r, _, _)| r).max().unwrap_or(0).max(0);
+        let lhs_left = matched.iter().map(|&(_, c, _)| c).min().unwrap_or(0).min(0);
+        let lhs_right = matched.iter().map(|&(_, c, _)| c).max().unwrap_or(0).max(0);
+        let rhs_left = replaced.iter().map(|&(_, c, _)| c).min().unwrap_or(0).min(0);
+        let separator = lhs_right - lhs_left + 1;
+        let rhs_origin = separator + 1 - rhs_left;
+
+        let mut block = vec![Vec::<char>::default(); (bottom - top + 1) as usize];
+        let mut put = |r: i32, c: i32, p: char| {
+            let row = &mut block[(r - top) as usize];
+            if row.len() <= c as usize {
+                row.resize(c as usize + 1, ' ');
+            }
+            row[c as usize] = p;
         };
-        self.rules.get_mut(&s).unwrap().push(rule);
+        put(0, -lhs_left, '@');
+        put(0, separator, '@');
+        put(0, rhs_origin, '@');
+        matched.iter().for_each(|&(r, c, p)| put(r, c - lhs_left, p));
+        replaced.iter().for_each(|&(r, c, p)| put(r, c + rhs_origin, p));
+        let rhs_all = block.iter()
+            .map(|row| String::from_iter(row.iter()))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let (ro, co) = Self::origin(rule.lhs, &rhs_all, '@', 0);
+        let (rm, cm) = Self::origin(rule.lhs, &rhs_all, '@', 1);
+        let (rq, cq) = Self::origin(rule.lhs, &rhs_all, '@', 2);
+        let lhs_all = match key {
+            Key::Char(key) => rule.lhs_all.chars().enumerate()
+                .map(|(i, c)| if i == 2 { key } else { c })
+                .collect(),
+            _ => rule.lhs_all.clone(),
+        };
+        Rule { lhs_all, rhs_all, key, ro, co, rm, cm, rq, cq, ..rule.clone() }
+    }
+
+    pub fn load(filename: &str) -> Result<Grammar2D, GrammarError> {
+        Self::load_with(filename, |grammar, includes, problems| {
+            grammar.parse_file(filename, includes, problems)
+        })
+    }
+
+    /// Loads a program from its already read (possibly gzipped) content; `filename` locates
+    /// the problems found and the included files. Included files found in `files`, by
+    /// canonical path, are not read again.
+    pub fn load_from(filename: &str, content: &[u8], files: HashMap<PathBuf, Vec<u8>>) -> Result<Grammar2D, GrammarError> {
+        Self::load_with(filename, |grammar, includes, problems| {
+            grammar.files = files;
+            grammar.parse(filename, decompress(content)?, includes, problems)
+        })
+    }
+
+    /// Files included directly by a program of the given content, located as by `#<`.
+    pub fn included_files(filename: &str, content: &[u8]) -> Vec<PathBuf> {
+        let Ok(g) = decompress(content) else {
+            return vec![];
+        };
+        g.lines().map_while(Result::ok)
+            .filter(|line| line.starts_with("#<"))
+            .map(|line| Self::include_path(filename, &line))
+            .map(|path| resolve_program(&path).unwrap_or(path))
+            .collect()
+    }
+
+    // file named by an include line, relative to the including file
+    fn include_path(filename: &str, line: &str) -> PathBuf {
+        let mut path = PathBuf::from(filename);
+        path.pop();
+        path.push(String::from_iter(line.chars().skip(2)).trim());
+        path
     }
 
-    pub fn load(&mut self, filename: &str) {
-        //println!("{}",filename);
-        let mut lhs: Vec<String> = vec![];
+    fn load_with(filename: &str,
+                 parse: impl FnOnce(&mut Self, &mut Vec<PathBuf>, &mut Vec<Problem>) -> std::io::Result<()>,
+    ) -> Result<Grammar2D, GrammarError> {
+        let mut grammar = Grammar2D::default();
+        let mut problems = Vec::<Problem>::default();
+        let mut includes = Vec::<PathBuf>::default();
+
+        if let Err(e) = parse(&mut grammar, &mut includes, &mut problems) {
+            problems.push(Problem {
+                file: filename.to_string(),
+                line: 0,
+                column: 0,
+                message: format!("cannot read grammar file: {}", e),
+            });
+        }
+        // variables may be declared after the rules using them
+        for rule in grammar.written_rules() {
+            let problem = |message: String| Problem {
+                file: grammar.sources[rule.source].clone(),
+                line: rule.line,
+                column: 1,
+                message,
+            };
+            for condition in rule.conditions.iter() {
+                let name = &condition.variable;
+                if !REWARD_VARIABLES.contains(&name.as_str()) && !grammar.variables.iter().any(|(v, _)| v == name) {
+                    problems.push(problem(format!("variable `{}` is not declared, e.g. `#${} 0`", name, name)));
+                }
+            }
+            for effect in rule.effects.iter() {
+                let name = &effect.variable;
+                if REWARD_VARIABLES.contains(&name.as_str()) {
+                    problems.push(problem(format!("`{}` can only be changed by rewards", name)));
+                } else if !grammar.variables.iter().any(|(v, _)| v == name) {
+                    problems.push(problem(format!("variable `{}` is not declared, e.g. `#${} 0`", name, name)));
+                }
+            }
+        }
+        grammar.files.clear();
+        if !problems.is_empty() {
+            return Err(GrammarError { problems });
+        }
+        if grammar.seeds.is_empty() {
+            grammar.seeds.push(Start {ul: 'c', lr: 'c', c: 'c', source: 0, line: 0});
+        }
+        Ok(grammar)
+    }
+
+    fn include(&mut self, filename: &str, line_no: usize, line: &str,
+               includes: &mut Vec<PathBuf>, problems: &mut Vec<Problem>) {
+        let path = Self::include_path(filename, line);
+        let included = path.to_string_lossy().to_string();
+        let problem = |message: String| Problem {
+            file: filename.to_string(), line: line_no, column: 3, message,
+        };
+        match resolve_program(&path).unwrap_or(path).canonicalize() {
+            Ok(canonical) if includes.contains(&canonical) => {
+                let mut cycle = includes.iter()
+                    .skip_while(|&include| include != &canonical)
+                    .map(|include| include.to_string_lossy().to_string())
+                    .collect::<Vec<_>>();
+                cycle.push(canonical.to_string_lossy().to_string());
+                problems.push(problem(format!("include cycle: {}", cycle.join(" -> "))));
+            }
+            // a library included again, e.g. by both sides of a diamond, would add its rules twice
+            Ok(canonical) if self.sources.iter().any(|source| Self::canonical(source) == canonical) => {}
+            Ok(_) => {
+                if let Err(e) = self.parse_file(&included, includes, problems) {
+                    problems.push(problem(format!("cannot read included file {}: {}", included, e)));
+                }
+            }
+            Err(e) => {
+                problems.push(problem(format!("cannot read included file {}: {}", included, e)));
+            }
+        }
+    }
+
+    // canonical path of a program file, used to tell included files apart
+    fn canonical(filename: &str) -> PathBuf {
+        let path = resolve_program(Path::new(filename)).unwrap_or_else(|| PathBuf::from(filename));
+        path.canonicalize().unwrap_or(path)
+    }
+
+    fn parse_file(&mut self, filename: &str, includes: &mut Vec<PathBuf>,
+                  problems: &mut Vec<Problem>) -> std::io::Result<()> {
+        if let Some(content) = self.files.get(&Self::canonical(filename)).cloned() {
+            return self.parse(filename, decompress(content.as_slice())?, includes, problems);
+        }
+        let path = resolve_program(Path::new(filename)).unwrap_or_else(|| PathBuf::from(filename));
+        let g = decompress(BufReader::new(File::open(path)?))?;
+        self.parse(filename, g, includes, problems)
+    }
+
+    fn parse(&mut self, filename: &str, g: impl BufRead, includes: &mut Vec<PathBuf>,
+             problems: &mut Vec<Problem>) -> std::io::Result<()> {
+        includes.push(Self::canonical(filename));
+        let source = self.sources.len();
+        self.sources.push(filename.to_string());
+
+        let mut lhs: Vec<(usize, String)> = vec![];
         let mut rhs: Vec<String> = vec![];
-        self.help = "".to_string();
-        let f = File::open(filename).expect("Cannot read grammar file.");
-        let g = BufReader::new(f);
-
-        g.lines()
-            .for_each(
-                |a_line| {
-                    let line = a_line.unwrap().clone();
-                    if let Some(fc) = line.chars().next() {
-                        if fc == '#' { //comment
-                            let second_char = Self::at_with_default(&line, 1, ' ');
-                            if second_char == '!' {
-                                self.help = String::from_iter(line.chars().skip(2));
-                            } else if second_char == '=' {
-                                let alias = Self::at_with_default(&line, 2, '=');
-                                let sound_file = String::from_iter(line.chars().skip(3));
-                                self.sounds.insert(alias, sound_file);
-                            }
-                        } else if fc == '^' {
-                            let c = Self::at_with_default(&line, 1, 's');
-                            let ul = Self::at_with_default(&line, 2, 'c');
-                            let lr = Self::at_with_default(&line, 3, 'c');
-                            self.seeds.push(Start { ul, lr, c });
-                        } else if fc == '=' {
-                            if !rhs.is_empty() && self._process(&lhs, &rhs) {
-                                lhs.clear();
-                                rhs.clear();
+        let problem = |line: usize, column: usize, message: String| Problem {
+            file: filename.to_string(), line, column, message,
+        };
+
+        for (idx, a_line) in g.lines().enumerate() {
+            let line_no = idx + 1;
+            let line = a_line?;
+            if let Some(fc) = line.chars().next() {
+                // directives that do not parse, e.g. `#---- section ----`, are comments
+                if fc == '#' { //comment
+                    let second_char = Self::at_with_default(&line, 1, ' ');
+                    if second_char == '!' {
+                        self.help = String::from_iter(line.chars().skip(2));
+                    } else if second_char == '=' {
+                        let alias = Self::at_with_default(&line, 2, '=');
+                        let sound_file = String::from_iter(line.chars().skip(3));
+                        if sound_file.is_empty() {
+                            problems.push(problem(line_no, 4, format!("sound alias `{}` has no sound file", alias)));
+                        }
+                        self.sounds.insert(alias, sound_file);
+                    } else if second_char == '*' {
+                        let name = String::from_iter(line.chars().skip(2));
+                        if let Some(key) = Key::from_name(name.trim()) {
+                            if !self.synchronous.contains(&key) {
+                                self.synchronous.push(key);
                             }
-                            lhs.push(line);
+                        }
+                    } else if second_char == '^' {
+                        let undo = String::from_iter(line.chars().skip(2));
+                        let parsed = undo.trim().split_once(' ').and_then(|(name, depth)| {
+                            Some((Key::from_name(name)?, depth.trim().parse::<usize>().ok()?))
+                        });
+                        if parsed.is_some() {
+                            self.undo = parsed;
+                        }
+                    } else if second_char == '$' {
+                        let variable = String::from_iter(line.chars().skip(2));
+                        let parsed = variable.trim().split_once(' ').and_then(|(name, value)| {
+                            Some((Self::variable_name(name)?, value.trim().parse::<i64>().ok()?))
+                        });
+                        match parsed {
+                            Some((name, _)) if REWARD_VARIABLES.contains(&name.as_str()) => problems.push(problem(line_no, 3,
+                                format!("variable `{}` is kept by the engine and cannot be declared", name))),
+                            Some((name, value)) => match self.variables.iter_mut().find(|(v, _)| *v == name) {
+                                Some(declared) => declared.1 = value,
+                                None => self.variables.push((name, value)),
+                            },
+                            None => {}
+                        }
+                    } else if second_char == '-' {
+                        let limit = String::from_iter(line.chars().skip(2));
+                        if let Ok(limit @ 1..) = limit.trim().parse::<i64>() {
+                            self.error_limit = Some(limit);
+                        }
+                    } else if second_char == '<' {
+                        // included rules never share a right-hand side with the including file
+                        if !rhs.is_empty() {
+                            self._process(source, &lhs, &rhs, problems);
                         } else {
-                            rhs.push(line);
+                            for (header_line, _) in lhs.iter() {
+                                problems.push(problem(*header_line, 1,
+                                    "rule header has no right-hand side before the include".to_string()));
+                            }
                         }
+                        lhs.clear();
+                        rhs.clear();
+                        self.include(filename, line_no, &line, includes, problems);
+                    }
+                } else if fc == '^' {
+                    let c = Self::at_with_default(&line, 1, 's');
+                    let ul = Self::at_with_default(&line, 2, 'c');
+                    let lr = Self::at_with_default(&line, 3, 'c');
+                    self.seeds.push(Start { ul, lr, c, source, line: line_no });
+                } else if fc == '=' {
+                    if !rhs.is_empty() && self._process(source, &lhs, &rhs, problems) {
+                        lhs.clear();
+                        rhs.clear();
+                    }
+                    if let Some(problem) = Self::check_header(filename, line_no, &line) {
+                        problems.push(problem);
                     } else {
-                        rhs.push(line);
+                        lhs.push((line_no, line));
                     }
+                } else {
+                    rhs.push(line);
                 }
-            );
+            } else {
+                rhs.push(line);
+            }
+        }
         if !rhs.is_empty() {
-            self._process(&lhs, &rhs);
+            self._process(source, &lhs, &rhs, problems);
         }
-        if self.seeds.is_empty() {
-            self.seeds.push(Start {ul: 'c', lr: 'c', c: 'c'});
+        includes.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // matched and replaced characters relative to the nonterminal and its replacement, whatever the layout
+    fn cells(rule: &Rule) -> (Vec<(i32, i32, char)>, Vec<(i32, i32, char)>) {
+        let horizontal = rule.cq > rule.co;
+        let (mut matched, mut replaced) = (vec![], vec![]);
+        for (r, row) in rule.rhs_all.split('\n').enumerate() {
+            for (c, p) in row.chars().enumerate() {
+                let (r, c) = (r as i32, c as i32);
+                let (lhs_side, rhs_side) = if horizontal { (c < rule.cm, c > rule.cm) } else { (r < rule.rm, r > rule.rm) };
+                if p == ' ' {
+                    continue;
+                }
+                if lhs_side && (r - rule.ro, c - rule.co) != (0, 0) {
+                    matched.push((r - rule.ro, c - rule.co, if p == '@' { rule.lhs } else { p }));
+                } else if rhs_side && (r - rule.rq, c - rule.cq) != (0, 0) {
+                    replaced.push((r - rule.rq, c - rule.cq, if p == '@' { rule.rep } else { p }));
+                }
+            }
         }
+        matched.sort();
+        replaced.sort();
+        (matched, replaced)
+    }
+
+    fn rule_of(grammar: &Grammar2D, key: char) -> &Rule {
+        grammar.rules[&'a'].iter().find(|rule| rule.key == Key::Char(key))
+            .unwrap_or_else(|| panic!("no rule of key `{}`", key))
     }
-}
\ No newline at end of file
+
+    // every variant of `rot` and `flip` equals the same rule turned by hand
+    #[test]
+    fn variants_equal_hand_written_rules() {
+        let cases = [
+            ("==adb77 rot=saw flip=f\n@~@@a\n x  y\n",
+             "==asb77\n @\nx~\n @\n @\nya\n==aab77\nx  y\n~@@a@\n==awb77\n~x\n@\n@\nay\n@\n==afb77\n~@@a@\nx  y\n",
+             "safw"),
+            ("==adb77 cell=2 rot=s\n@x~~@@Xab\n", "==asb77\n@x\n~~\n@\n@X\nab\n", "s"),
+        ];
+        for (generated, by_hand, keys) in cases {
+            let load = |content: &str| Grammar2D::load_from("variants.cfg", content.as_bytes(), HashMap::new())
+                .unwrap_or_else(|error| panic!("{}", error));
+            let (generated, by_hand) = (load(generated), load(by_hand));
+            for key in keys.chars() {
+                assert_eq!(cells(rule_of(&generated, key)), cells(rule_of(&by_hand, key)), "key `{}`", key);
+            }
+        }
+    }
+
+    // decorative comments that start like a directive are comments, directives still apply
+    #[test]
+    fn directive_like_comments_are_comments() {
+        let program = "#---------- section ----------\n#*** notes ***\n#^^^^\n#$$$$\n#-3\n#*T\n==aTb77\n@@@\n";
+        let grammar = Grammar2D::load_from("comments.cfg", program.as_bytes(), HashMap::new())
+            .unwrap_or_else(|error| panic!("{}", error));
+        assert_eq!(grammar.error_limit, Some(3));
+        assert_eq!(grammar.synchronous, [Key::Char('T')]);
+        assert!(grammar.undo.is_none() && grammar.variables.is_empty());
+    }
+
+    // headers waiting for their right-hand side when an include starts are reported, not dropped
+    #[test]
+    fn headers_before_include_are_reported() {
+        let dir = std::env::temp_dir().join("zero-orphaned-headers");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("lib.cfg"), "==aTb77\n@@@\n").unwrap();
+        std::fs::write(dir.join("main.cfg"), "==Pa~\n#<lib.cfg\n~@@@P\n").unwrap();
+        let loaded = Grammar2D::load(&dir.join("main.cfg").to_string_lossy());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let problems = loaded.err().expect("orphaned header loads").problems;
+        assert_eq!(problems.len(), 1);
+        assert_eq!((problems[0].line, problems[0].column), (1, 1));
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt::Write;
+
+use crate::debugger;
+use crate::derivation::Derivation;
+use crate::grammar::{sibling_program, Grammar2D, Key};
+use crate::save::SavedGame;
+use crate::scores::HighScores;
+use crate::{FAST_STEP, MIN_CHAR_HEIGHT, MIN_CHAR_WIDTH, SLOW_STEP};
+
+const USAGE: &str = "usage: zero run --headless <program.cfg> [--rows R] [--cols C] [--steps N] \
+    [--keys KEYS] [--every N] [--seed S] [--save FILE] [--scores FILE] [--debug] [--ansi]";
+
+// fast clock ticks per tick of the slow clock, as in the window
+const SLOW_TICKS: usize = (SLOW_STEP / FAST_STEP) as usize;
+// fast clock ticks per tick of the held key clock `M`, which the window runs ten times as often as the slow one
+const HELD_TICKS: usize = if SLOW_TICKS >= 10 { SLOW_TICKS / 10 } else { 1 };
+
+struct Options {
+    program: String,
+    rows: usize,
+    cols: usize,
+    steps: usize,
+    keys: Vec<Option<Key>>,
+    every: usize,
+    seed: u64,
+    save: Option<String>,
+    scores: Option<String>,
+    debug: bool,
+    ansi: bool,
+}
+
+fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut program = None;
+    let mut options = Options {
+        program: String::new(),
+        rows: MIN_CHAR_HEIGHT as usize,
+        cols: MIN_CHAR_WIDTH as usize,
+        steps: 1000,
+        keys: vec![],
+        every: 0,
+        seed: rand::random(),
+        save: None,
+        scores: None,
+        debug: false,
+        ansi: false,
+    };
+    let mut it = args.iter();
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().ok_or_else(|| format!("missing value of {}", arg));
+        let number = |value: &String| value.parse::<usize>().map_err(|_| format!("invalid value `{}` of {}", value, arg));
+        match arg.as_str() {
+            "--headless" => {}
+            "--ansi" => options.ansi = true,
+            "--debug" => options.debug = true,
+            "--rows" => options.rows = number(value()?)?.max(2),
+            "--cols" => options.cols = number(value()?)?.max(1),
+            "--steps" => options.steps = number(value()?)?,
+            "--every" => options.every = number(value()?)?,
+            "--keys" => options.keys = Key::from_names(value()?)?,
+            "--seed" => options.seed = number(value()?)? as u64,
+            "--save" => options.save = Some(value()?.clone()),
+            "--scores" => options.scores = Some(value()?.clone()),
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => program = Some(arg.clone()),
+        }
+    }
+    options.program = program.ok_or_else(|| "missing program".to_string())?;
+    Ok(options)
+}
+
+/// Grid as text below a status line; row 0 is left to the status line as in the window.
+fn frame(derivation: &Derivation, status: &str, ansi: bool) -> String {
+    let (rows, cols) = derivation.size();
+    let mut out = format!("{}\n", status);
+    for row in 1..rows {
+        let mut line = String::new();
+        let mut colours = None;
+        for col in 0..cols {
+            let (c, fore, back) = derivation.cell(row, col);
+            if ansi && colours != Some((fore, back)) {
+                let _ = write!(line, "\x1b[{};{}m", 30 + fore.min(7), 40 + back.min(7));
+                colours = Some((fore, back));
+            }
+            line.push(c);
+        }
+        if ansi {
+            line.push_str("\x1b[0m");
+        }
+        out.push_str(line.trim_end_matches(' '));
+        out.push('\n');
+    }
+    out
+}
+
+fn start(program: &str, options: &Options) -> Result<Derivation, String> {
+    let grammar = Grammar2D::load(program).map_err(|error| error.to_string())?;
+    let mut derivation = Derivation::new(grammar, options.rows, options.cols, options.seed);
+    derivation.start();
+    Ok(derivation)
+}
+
+fn resume(saved: &SavedGame, options: &Options) -> Result<Derivation, String> {
+    let grammar = Grammar2D::load(&saved.program).map_err(|error| error.to_string())?;
+    let mut derivation = Derivation::new(grammar, options.rows, options.cols, options.seed);
+    derivation.load(&saved.state).map_err(|message| format!("{}\n", message))?;
+    Ok(derivation)
+}
+
+/// Entry point of `zero run --headless`: derives a program without a window, passing a key
+/// of `--keys` (if any left) and a fast clock tick at every step, and a slow clock tick as
+/// often as the window would. Prints the grid every `--every` steps and at the end, and with
+/// `--debug` the rule applied by each step. With `--save`, the game saved in the file (if
+/// any) is resumed and saved there at the end. Scores are only kept with `--scores`.
+pub fn run(args: &[String]) -> i32 {
+    let options = match parse_options(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            return 2;
+        }
+    };
+    let mut high_scores = match options.scores.as_ref().map(|path| HighScores::load(path.into())).transpose() {
+        Ok(high_scores) => high_scores.unwrap_or_default(),
+        Err(e) => {
+            eprintln!("cannot read high scores: {}", e);
+            return 1;
+        }
+    };
+    let saved = match options.save.as_deref().map(SavedGame::read).transpose() {
+        Ok(saved) => saved.flatten(),
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+    let mut program = saved.as_ref().map_or(options.program.clone(), |saved| saved.program.clone());
+    let started = match saved.as_ref() {
+        Some(saved) => resume(saved, &options),
+        None => start(&program, &options),
+    };
+    let mut derivation = match started {
+        Ok(derivation) => derivation,
+        Err(message) => {
+            eprint!("{}", message);
+            return 1;
+        }
+    };
+    let (mut score, mut time, mut errors) = saved.as_ref()
+        .map_or((0_i64, 0_i64, 0_i64), |saved| (saved.score, saved.time, saved.errors));
+    let status = |score, time, errors, derivation: &Derivation| {
+        let variables = derivation.variables().into_iter()
+            .map(|(name, value)| format!(" {}: {}", name, value))
+            .collect::<String>();
+        format!(" Score: {} Time: {} Errors: {}{} Seed: {}", score, time, errors, variables, derivation.seed())
+    };
+    let mut printed = false;
+    'steps: for step in 1..=options.steps {
+        printed = false;
+        let mut keys = options.keys.get(step - 1).copied().flatten().into_iter().collect::<Vec<_>>();
+        keys.push(Key::Char('T'));
+        if step % HELD_TICKS == 0 {
+            keys.push(Key::Char('M'));
+        }
+        if step % SLOW_TICKS == 0 {
+            keys.push(Key::Char('B'));
+            time += 1;
+        }
+        for key in keys {
+            if derivation.grammar.undo.is_some_and(|(undo_key, _)| undo_key == key) {
+                if let Some((undone_score, undone_errors, _)) = derivation.undo() {
+                    (score, errors) = (undone_score, undone_errors);
+                }
+                continue;
+            }
+            let snapshot = (derivation.grammar.undo.is_some() && !matches!(key, Key::Char('T' | 'B' | 'M')))
+                .then(|| derivation.snapshot(score, errors));
+            derivation.set_reward(score, time, errors);
+            let candidates = if options.debug { derivation.applicable(key) } else { vec![] };
+            let result = derivation.step(key);
+            if options.debug && !result.applied.is_empty() {
+                println!("step {}", step);
+                for line in debugger::describe(&derivation, key, &result.applied, &candidates) {
+                    println!("{}", line);
+                }
+            }
+            if let Some(snapshot) = snapshot.filter(|_| !result.changes.is_empty()) {
+                derivation.push_undo(snapshot);
+            }
+            if result.sound_alias == '>' {
+                high_scores.record(&program, score, time);
+                let target = Grammar2D::navigation_target(&result.dbg_rule);
+                match sibling_program(&program, target) {
+                    Some(path) => {
+                        program = path.to_string_lossy().to_string();
+                        derivation = match start(&program, &options) {
+                            Ok(derivation) => derivation,
+                            Err(message) => {
+                                eprint!("{}", message);
+                                return 1;
+                            }
+                        };
+                    }
+                    None => {
+                        eprintln!("program left to {} at step {}", target, step);
+                        break 'steps;
+                    }
+                }
+                break;
+            }
+            score += result.score_delta as i64;
+            errors += result.errors_delta as i64;
+            if let Some(end) = result.end {
+                high_scores.record(&program, score, time);
+                eprintln!("{:?} at step {}{}", end.result, step,
+                          end.text.map(|text| format!(": {}", text)).unwrap_or_default());
+                let next = end.next.and_then(|next| sibling_program(&program, &next));
+                let Some(path) = next else {
+                    break 'steps;
+                };
+                program = path.to_string_lossy().to_string();
+                derivation = match start(&program, &options) {
+                    Ok(derivation) => derivation,
+                    Err(message) => {
+                        eprint!("{}", message);
+                        return 1;
+                    }
+                };
+                break;
+            }
+            if derivation.grammar.error_limit.is_some_and(|limit| errors >= limit) {
+                eprintln!("game over at step {}", step);
+                break 'steps;
+            }
+        }
+        if options.every > 0 && step % options.every == 0 {
+            print!("{}", frame(&derivation, &status(score, time, errors, &derivation), options.ansi));
+            printed = true;
+        }
+    }
+    if !printed {
+        print!("{}", frame(&derivation, &status(score, time, errors, &derivation), options.ansi));
+    }
+    high_scores.record(&program, score, time);
+    if let Some(path) = options.save.as_deref() {
+        let saved = SavedGame { program, score, time, errors, state: derivation.save() };
+        if let Err(e) = saved.write(path) {
+            eprintln!("cannot save to {}: {}", path, e);
+            return 1;
+        }
+    }
+    0
+}
@@ -1,10 +1,44 @@
 use bevy::prelude::KeyCode;
+use crate::grammar::Key;
 
+#[derive(Clone, Copy)]
 pub struct KeyCodeExt(pub KeyCode);
 
 // thanks to https://github.com/Jerald/bevy
 
 impl KeyCodeExt {
+    pub fn to_key(self, shift_down: bool) -> Option<Key> {
+        self.to_named_key().or_else(|| self.to_qwerty_char(shift_down).map(Key::Char))
+    }
+
+    pub fn to_named_key(self) -> Option<Key> {
+        let out = match self.0 {
+            KeyCode::ArrowUp => Key::Up,
+            KeyCode::ArrowDown => Key::Down,
+            KeyCode::ArrowLeft => Key::Left,
+            KeyCode::ArrowRight => Key::Right,
+            KeyCode::Enter => Key::Enter,
+            KeyCode::NumpadEnter => Key::Enter,
+            KeyCode::Escape => Key::Escape,
+            KeyCode::Backspace => Key::Backspace,
+            KeyCode::F1 => Key::Function(1),
+            KeyCode::F2 => Key::Function(2),
+            KeyCode::F3 => Key::Function(3),
+            KeyCode::F4 => Key::Function(4),
+            KeyCode::F5 => Key::Function(5),
+            KeyCode::F6 => Key::Function(6),
+            KeyCode::F7 => Key::Function(7),
+            KeyCode::F8 => Key::Function(8),
+            KeyCode::F9 => Key::Function(9),
+            KeyCode::F10 => Key::Function(10),
+            KeyCode::F11 => Key::Function(11),
+            KeyCode::F12 => Key::Function(12),
+            _ => return None,
+        };
+
+        Some(out)
+    }
+
     pub fn to_qwerty_char(self, shift_down: bool) -> Option<char> {
         if shift_down {
             self.to_qwerty_char_with_shift()
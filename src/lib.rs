@@ -0,0 +1,7 @@
+//! Engine of zero without a frontend: grammar programs, their derivation and the tools
+//! checking them. The window, audio and terminal rendering live in the `zero` binary.
+
+pub mod grammar;
+pub mod derivation;
+pub mod format;
+pub mod lint;
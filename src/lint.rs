@@ -0,0 +1,166 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::grammar::{sibling_program, Grammar2D, Problem, Rule};
+
+// characters with a special meaning when used as a rule replacement
+const SPECIAL_REPLACEMENTS: &str = " ~$&";
+
+// navigation targets that leave the engine instead of loading a program
+const QUIT_TARGETS: [&str; 2] = ["quit", "exit"];
+
+fn problem(filename: &str, line: usize, message: String) -> Problem {
+    Problem { file: filename.to_string(), line, column: 1, message }
+}
+
+fn source(grammar: &Grammar2D, source: usize) -> &str {
+    grammar.sources.get(source).map(|s| s.as_str()).unwrap_or("")
+}
+
+/// Symbols a rule requires in the matched part of its pattern, its own nonterminal excluded.
+fn read_symbols(rule: &Rule) -> BTreeSet<char> {
+    let horizontal = rule.cq > rule.co;
+    let mut symbols = BTreeSet::new();
+    for (r, row) in rule.rhs_all.split('\n').enumerate() {
+        if !horizontal && r as i32 >= rule.rm {
+            break;
+        }
+        for (c, p) in row.chars().enumerate() {
+            if horizontal && c as i32 >= rule.cm {
+                break;
+            }
+            match p {
+                '@' | ' ' | '!' | '~' => {}
+                '&' => { symbols.insert(rule.ctx); }
+                '%' => { symbols.insert(rule.ctx); symbols.insert(rule.ctx_rep); }
+                _ => { symbols.insert(p); }
+            }
+        }
+    }
+    symbols
+}
+
+/// Findings of the static checks: problems fail the lint, warnings only point at suspicious rules.
+#[derive(Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub warnings: Vec<Problem>,
+}
+
+/// Static checks of a grammar program; returns every problem found, load errors included.
+pub fn lint(filename: &str) -> Report {
+    let grammar = match Grammar2D::load(filename) {
+        Ok(grammar) => grammar,
+        Err(error) => return Report { problems: error.problems, ..Report::default() },
+    };
+    let mut problems = Vec::<Problem>::default();
+    let mut warnings = Vec::<Problem>::default();
+
+    let mut rules = grammar.rules.values().flatten().collect::<Vec<_>>();
+    rules.sort_by_key(|rule| (rule.source, rule.line));
+
+    // symbols each rule requires in its left-hand side pattern or context
+    let reads = rules.iter().map(|rule| read_symbols(rule)).collect::<Vec<_>>();
+    // anchor replacements without rules that another rule expects to find, reported once per symbol
+    let mut dead_ends = BTreeMap::<char, (&Rule, usize, &Rule)>::new();
+    for (index, rule) in rules.iter().enumerate() {
+        let anchors = rule.rhs_all.chars().filter(|&c| c == '@').count();
+        if rule.rq < 0 {
+            problems.push(problem(source(&grammar, rule.source), rule.line, format!(
+                "rule `={}` has {} `@` anchor(s) in its right-hand side, 3 are required",
+                rule.lhs_all, anchors)));
+        }
+        if let Some(next) = rule.end.as_ref().and_then(|end| end.next.as_ref()) {
+            if sibling_program(filename, next).is_none() {
+                problems.push(problem(source(&grammar, rule.source), rule.line, format!(
+                    "next program `{}` does not exist", next)));
+            }
+        }
+        if rule.sound == '>' {
+            let target = Grammar2D::navigation_target(&rule.lhs_all);
+            if !QUIT_TARGETS.contains(&target) && sibling_program(filename, target).is_none() {
+                problems.push(problem(source(&grammar, rule.source), rule.line, format!(
+                    "navigation target `{}` does not exist", target)));
+            }
+        } else if !SPECIAL_REPLACEMENTS.contains(rule.rep) && !grammar.rules.contains_key(&rule.rep) {
+            let reader = rules.iter().zip(reads.iter()).enumerate()
+                .find(|(other, (_, read))| *other != index && read.contains(&rule.rep))
+                .map(|(_, (reader, _))| *reader);
+            if let Some(reader) = reader {
+                dead_ends.entry(rule.rep).or_insert((rule, 0, reader)).1 += 1;
+            }
+        }
+    }
+    for (symbol, (rule, count, reader)) in dead_ends {
+        warnings.push(problem(source(&grammar, rule.source), rule.line, format!(
+            "symbol `{}` is written by the anchor of {} rule(s) and read by the rule at {}:{}, but has no rules",
+            symbol, count, source(&grammar, reader.source), reader.line)));
+    }
+
+    for seed in grammar.seeds.iter() {
+        if !grammar.rules.contains_key(&seed.c) {
+            problems.push(problem(source(&grammar, seed.source), seed.line, format!(
+                "seed `{}` is never rewritten, no rule has it on the left-hand side", seed.c)));
+        }
+    }
+
+    let used_sounds = rules.iter().map(|rule| rule.sound).collect::<BTreeSet<_>>();
+    let mut aliases = grammar.sounds.iter().collect::<Vec<_>>();
+    aliases.sort();
+    for (alias, sound_file) in aliases {
+        if !used_sounds.contains(alias) {
+            problems.push(problem(filename, 0, format!(
+                "sound alias `{}` ({}) is not used by any rule", alias, sound_file)));
+        }
+    }
+    let used_variables = rules.iter()
+        .flat_map(|rule| rule.conditions.iter().map(|condition| &condition.variable)
+            .chain(rule.effects.iter().map(|effect| &effect.variable)))
+        .collect::<BTreeSet<_>>();
+    for (name, _) in grammar.variables.iter() {
+        if !used_variables.contains(name) {
+            problems.push(problem(filename, 0, format!("variable `{}` is not used by any rule", name)));
+        }
+    }
+    problems.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    warnings.sort_by(|a, b| (&a.file, a.line).cmp(&(&b.file, b.line)));
+    Report { problems, warnings }
+}
+
+/// Entry point of `zero lint <program.cfg>...`; returns the process exit code.
+pub fn run(filenames: &[String]) -> i32 {
+    if filenames.is_empty() {
+        eprintln!("usage: zero lint <program.cfg>...");
+        return 2;
+    }
+    let mut failed = false;
+    for filename in filenames {
+        let report = lint(filename);
+        for problem in report.problems.iter() {
+            println!("{}", problem);
+            failed = true;
+        }
+        for warning in report.warnings.iter() {
+            println!("{} (warning)", warning);
+        }
+    }
+    if failed { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `b` is written by the anchor of `a` and read next to the anchor of `c`, but never rewritten
+    #[test]
+    fn reports_anchor_replacements_read_without_rules() {
+        let filename = std::env::temp_dir().join("zero-lint-dead-end.cfg");
+        std::fs::write(&filename, "^a\n==aTb77\n@@@\n==aTe77\n@@@\n==cTc77\n@b@@d\n").unwrap();
+        let report = lint(&filename.to_string_lossy());
+        std::fs::remove_file(&filename).unwrap();
+        assert!(report.problems.is_empty(), "{:?}", report.problems.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].line, 2);
+        assert!(report.warnings[0].message.starts_with("symbol `b` is written by the anchor of 1 rule(s)"),
+                "{}", report.warnings[0]);
+    }
+}
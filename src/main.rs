@@ -1,21 +1,33 @@
 pub mod terminal;
-pub mod grammar;
-pub mod derivation;
+mod debugger;
+mod headless;
 mod input;
+mod outcome;
+mod program;
+mod replay;
+mod save;
+mod scores;
 
 use std::collections::HashMap;
+use zero::{derivation, format, grammar, lint};
 use bevy::diagnostic::{DiagnosticsStore, FrameTimeDiagnosticsPlugin};
 use bevy::prelude::*;
 use terminal::TerminalPlugin;
 use terminal::TerminalEvent;
+use crate::debugger::Debugger;
 use crate::derivation::Derivation;
-use crate::grammar::Grammar2D;
+use crate::grammar::{sibling_program, Grammar2D, GrammarError, Key, Problem};
 use crate::input::KeyCodeExt;
+use crate::outcome::{Ending, Outcome};
+use crate::program::{program_asset_path, program_source, Program, ProgramPlugin, PROGRAM_SOURCE};
+use crate::replay::Replay;
+use crate::save::SavedGame;
+use crate::scores::HighScores;
 use crate::terminal::{Terminal, TerminalNew, TerminalReady};
 use std::env;
 use std::num::NonZeroU8;
 use bevy::audio::AudioSource;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use bevy::app::AppExit;
 use bevy::time::common_conditions::on_timer;
@@ -30,6 +42,7 @@ const MIN_CHAR_WIDTH: u16 = 80;
 const MIN_CHAR_HEIGHT: u16 = 35;
 const NUM_DERIVATIONS_PER_TICK: u8 = 1;
 const PROGRAM_FILE: &str = "assets/programs/menu.cfg";
+const MENU_FILE: &str = "menu.cfg";
 
 #[derive(States, Clone, Eq, Debug, Hash, PartialEq, Copy, Default)]
 enum AppState {
@@ -51,6 +64,35 @@ struct KeyRepeatTiming(HashMap<KeyCode, f64>);
 #[derive(Resource)]
 struct ProgramFile(String);
 
+#[derive(Resource, Default)]
+struct ProgramError(Option<GrammarError>);
+
+/// Directory served by the program asset source.
+#[derive(Resource)]
+struct ProgramRoot(PathBuf);
+
+#[derive(Resource, Default)]
+struct ProgramAsset {
+    file: String,
+    handle: Option<Handle<Program>>,
+}
+
+/// Derivation of the running program.
+#[derive(Component, Deref, DerefMut)]
+struct ActiveDerivation(Derivation);
+
+/// Seed given by `--seed`, used for every derivation started; random if not given.
+#[derive(Resource)]
+struct RandomSeed(Option<u64>);
+
+/// File given by `--save`: the game saved in it is resumed at start, and the game running is
+/// saved to it on exit.
+#[derive(Resource, Default)]
+struct SaveFile {
+    path: Option<String>,
+    resume: Option<SavedGame>,
+}
+
 #[derive(Resource)]
 struct RewardAccumulator {
     score: i64,
@@ -58,15 +100,82 @@ struct RewardAccumulator {
     errors: i64,
 }
 
+/// Removes `name value` from the command line arguments and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let i = args.iter().position(|arg| arg == name)?;
+    if i + 1 >= args.len() {
+        eprintln!("missing value of {}", name);
+        std::process::exit(2);
+    }
+    args.remove(i);
+    Some(args.remove(i))
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    if args.len() > 1 && args[1] == "lint" {
+        std::process::exit(lint::run(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "fmt" {
+        std::process::exit(format::run(&args[2..]));
+    }
+    if args.len() > 1 && args[1] == "run" {
+        if args.iter().any(|arg| arg == "--headless") {
+            std::process::exit(headless::run(&args[2..]));
+        }
+        args.remove(1);
+    }
+    let seed = take_option(&mut args, "--seed").map(|seed| seed.parse::<u64>().unwrap_or_else(|_| {
+        eprintln!("invalid value `{}` of --seed", seed);
+        std::process::exit(2);
+    }));
+    let replay = match (take_option(&mut args, "--record"), take_option(&mut args, "--replay")) {
+        (Some(_), Some(_)) => {
+            eprintln!("--record and --replay cannot be used together");
+            std::process::exit(2);
+        }
+        (Some(path), None) => Replay::record(&path),
+        (None, Some(path)) => Replay::play(&path),
+        (None, None) => Ok(Replay::default()),
+    }.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    let debug = match args.iter().position(|arg| arg == "--debug") {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    };
+    let save_path = take_option(&mut args, "--save");
+    let high_scores = take_option(&mut args, "--scores").map(PathBuf::from).or_else(HighScores::default_path)
+        .map(HighScores::load)
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("cannot read high scores: {}", e);
+            None
+        })
+        .unwrap_or_default();
+    let resume = save_path.as_deref().map(SavedGame::read).transpose().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    }).flatten();
     let (program_file, fast_step, slow_step) = match args.len() {
         1 => (PROGRAM_FILE.to_string(), FAST_STEP, SLOW_STEP),
         2 => (args[1].clone(), FAST_STEP, SLOW_STEP),
         3 => (args[1].clone(), args[2].parse::<f64>().unwrap(), SLOW_STEP),
         _ => (args[1].clone() , args[2].parse::<f64>().unwrap(), args[3].parse::<f64>().unwrap()),
     };
+    let program_file = replay.program().map(String::from)
+        .or_else(|| resume.as_ref().map(|saved| saved.program.clone()))
+        .unwrap_or(program_file);
+    let program_dir = Path::new(&program_file).parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let program_root = program_dir.canonicalize().unwrap_or(program_dir.to_path_buf());
     App::new()
+        .register_asset_source(PROGRAM_SOURCE, program_source(&program_root))
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 mode: WindowMode::BorderlessFullscreen,
@@ -77,8 +186,18 @@ fn main() {
             ..default()
         }))
         .add_plugins(TerminalPlugin::new())
+        .add_plugins(ProgramPlugin::new(program_root.clone()))
         .add_plugins(FrameTimeDiagnosticsPlugin::default())
         .insert_resource(ProgramFile(program_file.clone()))
+        .init_resource::<ProgramError>()
+        .init_resource::<ProgramAsset>()
+        .init_resource::<Outcome>()
+        .insert_resource(ProgramRoot(program_root))
+        .insert_resource(RandomSeed(seed))
+        .insert_resource(replay)
+        .insert_resource(SaveFile { path: save_path, resume })
+        .insert_resource(Debugger::new(debug))
+        .insert_resource(high_scores)
         .insert_resource(KeyRepeatTiming(Default::default()))
         .insert_resource(RewardAccumulator{
             score: 0,
@@ -86,12 +205,16 @@ fn main() {
             errors: 0
         })
         .insert_resource(Time::<Fixed>::from_seconds(fast_step))
-        .add_systems(Startup, prepare_audio)
+        .add_systems(Startup, init_audio)
         .init_state::<AppState>()
         //.add_system(display_fps_system)
         //.add_system(bevy::window::exit_on_all_closed)
         .add_systems(Update, clear_grammar_system)
         .add_systems(Update, start_grammar_system)
+        .add_systems(Update, program_error_system)
+        .add_systems(Update, outcome_system)
+        .add_systems(Update, high_score_system)
+        .add_systems(Update, program_reload_system)
         .add_systems(Update, check_audio_loading)
         .add_systems(FixedUpdate, grammar_derivation_system_t.pipe(grammar_derivation_system))
         .add_systems(Update, grammar_derivation_system_b
@@ -100,6 +223,7 @@ fn main() {
         .add_systems(Update, grammar_derivation_system_m
             .pipe(grammar_derivation_system)
             .run_if(on_timer(Duration::from_secs_f64(0.1*slow_step))))
+        .add_systems(Last, (save_game_system, record_score_system, replay_flush_system))
         .run();
 }
 
@@ -120,13 +244,11 @@ fn check_audio_loading(mut audio_state: ResMut<AudioState>, asset_server: ResMut
     audio_state.audio_loaded = true;
 }
 
-fn prepare_audio(mut commands: Commands, program_file: Res<ProgramFile>,
-                 asset_server: ResMut<AssetServer>
-) {
-
-    let mut grammar = Grammar2D::default();
-    grammar.load(&program_file.0);
+fn init_audio(mut commands: Commands, asset_server: ResMut<AssetServer>) {
+    prepare_audio(&mut commands, &Grammar2D::default(), &asset_server);
+}
 
+fn prepare_audio(commands: &mut Commands, grammar: &Grammar2D, asset_server: &AssetServer) {
     let mut sound_handles =  HashMap::<char, Handle<AudioSource>>::new();
     for (sound_alias, sound_file) in grammar.sounds.iter() {
         let sound_handle = asset_server.load(sound_file);
@@ -139,12 +261,43 @@ fn prepare_audio(mut commands: Commands, program_file: Res<ProgramFile>,
     };
 
     commands.insert_resource(audio_state);
+}
 
+/// Grammar of the program file, or `None` while its asset is still loading. Programs outside
+/// of the program asset source are read directly and not watched for changes.
+fn load_program(program_file: &str,
+                root: &ProgramRoot,
+                program_asset: &mut ProgramAsset,
+                programs: &Assets<Program>,
+                asset_server: &AssetServer,
+) -> Option<Result<Grammar2D, GrammarError>> {
+    let Some(asset_path) = program_asset_path(&root.0, program_file) else {
+        return Some(Grammar2D::load(program_file));
+    };
+    if program_asset.handle.is_none() || program_asset.file != program_file {
+        program_asset.file = program_file.to_string();
+        program_asset.handle = Some(asset_server.load(asset_path));
+    }
+    let handle = program_asset.handle.as_ref()?;
+    if let Some(program) = programs.get(handle) {
+        return Some(program.0.clone());
+    }
+    if let Some(LoadState::Failed(error)) = asset_server.get_load_state(handle) {
+        return Some(Err(GrammarError {
+            problems: vec![Problem {
+                file: program_file.to_string(),
+                line: 0,
+                column: 0,
+                message: error.to_string(),
+            }],
+        }));
+    }
+    None
 }
 
 fn clear_grammar_system(mut commands: Commands,
     mut is_new: EventReader<TerminalNew>,
-    derivation: Query<Entity, With<Derivation>>,
+    derivation: Query<Entity, With<ActiveDerivation>>,
 ) {
     for _event in is_new.read() {
         for id in derivation.iter() {
@@ -155,33 +308,261 @@ fn clear_grammar_system(mut commands: Commands,
 
 fn start_grammar_system(mut commands: Commands,
     terminals: Query<&Terminal>,
-    derivation: Query<Entity, With<Derivation>>,
+    derivation: Query<Entity, With<ActiveDerivation>>,
     program_file: Res<ProgramFile>,
+    program_root: Res<ProgramRoot>,
+    (random_seed, mut replay): (Res<RandomSeed>, ResMut<Replay>),
+    (mut save_file, mut accumulator): (ResMut<SaveFile>, ResMut<RewardAccumulator>),
+    mut program_asset: ResMut<ProgramAsset>,
+    programs: Res<Assets<Program>>,
+    mut program_error: ResMut<ProgramError>,
     mut is_ready: EventReader<TerminalReady>,
     mut term: EventWriter<TerminalEvent>,
     mut next_state: ResMut<NextState<AppState>>,
     state: Res<State<AppState>>,
     asset_server: ResMut<AssetServer>,
 ) {
-    if derivation.iter().count() <= 0  {
+    if derivation.iter().count() <= 0 && program_error.0.is_none() {
         if let Some(_ready) = is_ready.read().next() {
             if let Some(terminal) = terminals.iter().next() {
+                let Some(loaded) = load_program(&program_file.0, &program_root,
+                    &mut program_asset, &programs, &asset_server) else {
+                    return;
+                };
                 if state.get() != &AppState::Paused {
                     next_state.set(AppState::Paused);
                 }
-                let mut grammar = Grammar2D::default();
-                grammar.load(&program_file.0);
-                let mut derivation = Derivation::new(
-                    grammar, terminal.rows, terminal.cols);
+                match loaded {
+                    Ok(grammar) => {
+                        prepare_audio(&mut commands, &grammar, &asset_server);
 
-                for e in derivation.start() {
-                    term.send(e);
+                        let seed = replay.start(&program_file.0, terminal.rows, terminal.cols,
+                                                random_seed.0.unwrap_or_else(rand::random));
+                        let mut derivation = Derivation::new(
+                            grammar, terminal.rows, terminal.cols, seed);
+
+                        let resumed = match save_file.resume.take() {
+                            Some(saved) => match derivation.load(&saved.state) {
+                                Ok(cells) => {
+                                    accumulator.score = saved.score;
+                                    accumulator.time = saved.time;
+                                    accumulator.errors = saved.errors;
+                                    Some(cells)
+                                }
+                                Err(message) => {
+                                    eprintln!("cannot resume the saved game: {}", message);
+                                    None
+                                }
+                            },
+                            None => None,
+                        };
+                        for change in resumed.unwrap_or_else(|| derivation.start()) {
+                            term.send(TerminalEvent::from(&change));
+                        }
+                        commands.spawn(ActiveDerivation(derivation));
+                    }
+                    Err(error) => {
+                        eprint!("{}", error);
+                        show_program_error(terminal, &error, &mut term);
+                        program_error.0 = Some(error);
+                    }
                 }
-                commands.spawn(derivation);
+            }
+        }
+    }
+}
+
+fn show_program_error(terminal: &Terminal, error: &GrammarError, events: &mut EventWriter<TerminalEvent>) {
+    let msg = " Cannot load program  [space] reload  [q] menu  [e] quit";
+    events.send(TerminalEvent {
+        row: 0,
+        col: 0,
+        s: format!("{:<width$}", msg, width = terminal.cols),
+        attr: (Color::WHITE, Color::BLACK),
+    });
+    for (row, problem) in error.problems.iter().enumerate().take(terminal.rows.saturating_sub(1)) {
+        events.send(TerminalEvent {
+            row: row + 1,
+            col: 1,
+            s: problem.to_string(),
+            attr: (Color::Srgba(bevy::color::palettes::basic::RED), Color::BLACK),
+        });
+    }
+}
 
-                prepare_audio(commands, program_file, asset_server);
+/// Applies changes of the running program: compatible rules replace the old ones in place,
+/// otherwise the program is restarted (showing its problems, if any).
+fn program_reload_system(mut commands: Commands,
+    mut asset_events: EventReader<AssetEvent<Program>>,
+    (programs, program_asset): (Res<Assets<Program>>, Res<ProgramAsset>),
+    mut program_error: ResMut<ProgramError>,
+    mut derivation: Query<&mut ActiveDerivation>,
+    mut events: EventWriter<TerminalEvent>,
+    asset_server: Res<AssetServer>,
+) {
+    let Some(handle) = program_asset.handle.as_ref() else {
+        return;
+    };
+    for event in asset_events.read() {
+        if !event.is_modified(handle) {
+            continue;
+        }
+        match (derivation.iter_mut().next(), programs.get(handle).map(|program| &program.0)) {
+            (Some(mut derive), Some(Ok(grammar))) if derive.grammar.is_compatible(grammar) => {
+                prepare_audio(&mut commands, grammar, &asset_server);
+                derive.set_grammar(grammar.clone());
             }
+            _ => {
+                program_error.0 = None;
+                events.send(TerminalEvent::clear());
+            }
+        }
+    }
+}
+
+fn program_error_system(mut commands: Commands,
+    mut program_error: ResMut<ProgramError>,
+    program_file: Res<ProgramFile>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut events: EventWriter<TerminalEvent>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if program_error.0.is_none() {
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyE) {
+        exit.send(AppExit::Success);
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyQ) {
+        commands.insert_resource(menu_program(&program_file.0));
+    } else if !keyboard_input.just_pressed(KeyCode::Space) {
+        return;
+    }
+    program_error.0 = None;
+    events.send(TerminalEvent::clear());
+}
+
+/// Menu next to a program, left to with `q`; the default menu if there is none.
+fn menu_program(program: &str) -> ProgramFile {
+    let menu = sibling_program(program, MENU_FILE).map(|path| path.to_string_lossy().to_string());
+    ProgramFile(menu.unwrap_or(PROGRAM_FILE.to_string()))
+}
+
+/// Saves the game running to the file of `--save` when the engine exits.
+fn save_game_system(mut exit: EventReader<AppExit>,
+                    save_file: Res<SaveFile>,
+                    program_file: Res<ProgramFile>,
+                    accumulator: Res<RewardAccumulator>,
+                    mut derivation: Query<&mut ActiveDerivation>,
+) {
+    if exit.read().count() == 0 {
+        return;
+    }
+    let (Some(path), Some(mut derive)) = (save_file.path.as_deref(), derivation.iter_mut().next()) else {
+        return;
+    };
+    let saved = SavedGame {
+        program: program_file.0.clone(),
+        score: accumulator.score,
+        time: accumulator.time,
+        errors: accumulator.errors,
+        state: derive.save(),
+    };
+    if let Err(e) = saved.write(path) {
+        eprintln!("cannot save to {}: {}", path, e);
+    }
+}
+
+/// Keys of the ending of a game: space restarts the program or goes on with the next one,
+/// `q` goes to the menu and `e` quits. The reward starts over unless the game goes on.
+fn outcome_system(mut commands: Commands,
+                  mut outcome: ResMut<Outcome>,
+                  program_file: Res<ProgramFile>,
+                  keyboard_input: Res<ButtonInput<KeyCode>>,
+                  mut accumulator: ResMut<RewardAccumulator>,
+                  mut events: EventWriter<TerminalEvent>,
+                  mut exit: EventWriter<AppExit>,
+) {
+    if outcome.0.is_none() {
+        return;
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyE) {
+        exit.send(AppExit::Success);
+        return;
+    }
+    let next = outcome.0.as_ref().and_then(|ending| ending.next.clone());
+    if keyboard_input.just_pressed(KeyCode::KeyQ) {
+        commands.insert_resource(menu_program(&program_file.0));
+    } else if !keyboard_input.just_pressed(KeyCode::Space) {
+        return;
+    }
+    match next {
+        Some(next) if keyboard_input.just_pressed(KeyCode::Space) => commands.insert_resource(ProgramFile(next)),
+        _ => *accumulator = RewardAccumulator { score: 0, time: 0, errors: 0 },
+    }
+    outcome.0 = None;
+    events.send(TerminalEvent::clear());
+}
+
+/// Records the score of the game running when the engine exits.
+fn record_score_system(mut exit: EventReader<AppExit>,
+                       mut high_scores: ResMut<HighScores>,
+                       program_file: Res<ProgramFile>,
+                       accumulator: Res<RewardAccumulator>,
+                       derivation: Query<&ActiveDerivation>,
+) {
+    if exit.read().count() > 0 && !derivation.is_empty() {
+        high_scores.record(&program_file.0, accumulator.score, accumulator.time);
+    }
+}
+
+/// Writes out the records of the replay when the engine exits.
+fn replay_flush_system(mut exit: EventReader<AppExit>, mut replay: ResMut<Replay>) {
+    if exit.read().count() > 0 {
+        replay.flush();
+    }
+}
+
+/// Shows the high scores of the program at the bottom of the grid while it is paused, and
+/// the grid below them again when it runs.
+fn high_score_system(mut high_scores: ResMut<HighScores>,
+                     state: Res<State<AppState>>,
+                     (outcome, debugger): (Res<Outcome>, Res<Debugger>),
+                     program_file: Res<ProgramFile>,
+                     terminal: Query<&Terminal>,
+                     derivation: Query<&ActiveDerivation>,
+                     mut events: EventWriter<TerminalEvent>,
+) {
+    let (Some(terminal), Some(derive)) = (terminal.iter().next(), derivation.iter().next()) else {
+        high_scores.paused = None;
+        high_scores.shown.clear();
+        return;
+    };
+    if program_file.is_changed() {
+        high_scores.paused = None;
+        high_scores.shown.clear();
+        return;
+    };
+    if state.get() == &AppState::Paused && outcome.0.is_none() && !debugger.enabled {
+        let lines = match high_scores.paused.take() {
+            Some(lines) => lines,
+            None => high_scores.summary(&program_file.0, &derive.grammar),
+        };
+        if !lines.is_empty() {
+            let top = terminal.rows.saturating_sub(lines.len() + 3).max(1);
+            let shown = outcome::centred_box(terminal, &lines, top, Color::BLACK);
+            high_scores.shown = shown.iter()
+                .flat_map(|event| (event.col..event.col + event.s.chars().count()).map(move |col| (event.row, col)))
+                .collect();
+            shown.into_iter().for_each(|event| { events.send(event); });
         }
+        high_scores.paused = Some(lines);
+    } else if high_scores.paused.take().is_some() {
+        for change in derive.redraw(&high_scores.shown) {
+            events.send(TerminalEvent::from(&change));
+        }
+        high_scores.shown.clear();
     }
 }
 
@@ -206,10 +587,14 @@ fn grammar_derivation_system(time_step_code: In<KeyCode>,
                              mut next_state: ResMut<NextState<AppState>>,
                              mut key_repeat_times: ResMut<KeyRepeatTiming>,
                              mut keyboard_input: ResMut<ButtonInput<KeyCode>>,
-                             mut derivation: Query<&mut Derivation>,
+                             mut derivation: Query<&mut ActiveDerivation>,
                              mut events: EventWriter<TerminalEvent>,
-                             mut exit: EventWriter<AppExit>
+                             (mut exit, mut replay): (EventWriter<AppExit>, ResMut<Replay>),
+                             (mut debugger, mut outcome, mut high_scores): (ResMut<Debugger>, ResMut<Outcome>, ResMut<HighScores>),
 ) {
+    if outcome.0.is_some() {
+        return;
+    }
     let new_state = if state.get() == &AppState::Paused { AppState::Running } else { AppState::Paused };
 
     let current_time = time.elapsed_seconds_f64();
@@ -252,73 +637,139 @@ fn grammar_derivation_system(time_step_code: In<KeyCode>,
                         .unwrap_or(&current_time)) > 0.25)
                 }))
                 .chain(time_lapse.iter());
+            let mut keys = Vec::<Key>::default();
             for key_code in iter {
                 let shift_down = (key_code == &KeyCode::KeyT)
                     || (key_code == &KeyCode::KeyM)
                     || (key_code == &KeyCode::KeyB)
                     || keyboard_input.pressed(KeyCode::ShiftLeft)
                     || keyboard_input.pressed(KeyCode::ShiftRight);
-                if let Some(c) = KeyCodeExt(key_code.clone()).to_qwerty_char(shift_down) {
-                    if c == ' ' {
+                if let Some(c) = KeyCodeExt(key_code.clone()).to_key(shift_down) {
+                    if c == Key::Char(' ') {
                         if state.get() != &new_state {
                             next_state.set(new_state);
                         }
                         break;
                     }
-                    let mut repeat_times = 1;
-                    if c == 'B' {
-                        accumulator.time += 1;
-                    } else if c == 'T' {
-                        repeat_times = NUM_DERIVATIONS_PER_TICK;
+                    if debugger.enabled && c == debugger::STEP_KEY {
+                        keys.extend(['T', 'B', 'M'].map(Key::Char));
+                    } else {
+                        keys.push(c);
+                    }
+                }
+                if time_step_code.0 == KeyCode::KeyT {
+                    cleared.push(key_code.clone());
+                }
+            }
+            let clock = match time_step_code.0 {
+                KeyCode::KeyT => 'T',
+                KeyCode::KeyB => 'B',
+                _ => 'M',
+            };
+            for c in replay.keys(clock, keys) {
+                let mut repeat_times = 1;
+                if c == Key::Char('B') {
+                    accumulator.time += 1;
+                } else if c == Key::Char('T') {
+                    repeat_times = NUM_DERIVATIONS_PER_TICK;
+                }
+                if derive.grammar.undo.is_some_and(|(undo_key, _)| undo_key == c) {
+                    if let Some((score, errors, cells)) = derive.undo() {
+                        accumulator.score = score;
+                        accumulator.errors = errors;
+                        for change in cells.iter() {
+                            events.send(TerminalEvent::from(change));
+                        }
+                        events.send(status_event(terminal, &accumulator, derive, "undo"));
                     }
-                    for _ in 1..(repeat_times + 1) {
-                        let result = derive.step(c);
-                        if result.sound_alias == '>' {
-                            let mut new_program = PathBuf::from(program_file.0.clone());
-                            new_program.pop();
-                            let new_file = result.dbg_rule.split(" ").last().unwrap();
-                            new_program.push(new_file);
-                            if !new_program.exists() {
-                                eprintln!("Cannot open program {}", new_file);
-                                exit.send(AppExit::Error(NonZeroU8::new(2_u8).unwrap()));
-                            } else {
-                                let new_program = new_program.to_str().unwrap().to_string();
-                                next_state.set(AppState::Paused);
-                                commands.insert_resource(ProgramFile(new_program));
-                                events.send(TerminalEvent::clear());
-                            }
-                            break;
+                    continue;
+                }
+                // steps triggered by the player, not by a clock, can be undone
+                let snapshot = (derive.grammar.undo.is_some() && !matches!(c, Key::Char('T' | 'B' | 'M')))
+                    .then(|| derive.snapshot(accumulator.score, accumulator.errors));
+                let mut changed = false;
+                let mut stop = false;
+                for _ in 1..(repeat_times + 1) {
+                    derive.set_reward(accumulator.score, accumulator.time, accumulator.errors);
+                    let candidates = if debugger.enabled {
+                        for event in debugger.restore(derive) {
+                            events.send(event);
                         }
-                        accumulator.score += result.score_delta as i64;
-                        accumulator.errors += result.errors_delta as i64;
-                        for e in result.terminal_events {
-                            events.send(e);
-                            let msg_left = format!("Score: {} Time: {} Errors: {}",
-                                                   accumulator.score,
-                                                   accumulator.time,
-                                                   accumulator.errors);
-                            let msg_pad = std::iter::repeat(" ")
-                                .take(terminal.cols - 2 - msg_left.chars().count() - result.dbg_rule.chars().count())
-                                .collect::<String>();
-                            let msg = format!(" {}{}{} ", msg_left, msg_pad, result.dbg_rule);
-
-                            events.send(TerminalEvent {
-                                row: 0,
-                                col: 0,
-                                s: msg,
-                                attr: (Color::WHITE, Color::BLACK),
-                            });
+                        derive.applicable(c)
+                    } else {
+                        vec![]
+                    };
+                    let result = derive.step(c);
+                    if result.sound_alias == '>' {
+                        high_scores.record(&program_file.0, accumulator.score, accumulator.time);
+                        let new_file = Grammar2D::navigation_target(&result.dbg_rule);
+                        if let Some(new_program) = sibling_program(&program_file.0, new_file) {
+                            let new_program = new_program.to_str().unwrap().to_string();
+                            next_state.set(AppState::Paused);
+                            commands.insert_resource(ProgramFile(new_program));
+                            events.send(TerminalEvent::clear());
+                        } else {
+                            eprintln!("Cannot open program {}", new_file);
+                            exit.send(AppExit::Error(NonZeroU8::new(2_u8).unwrap()));
+                        }
+                        break;
+                    }
+                    accumulator.score += result.score_delta as i64;
+                    accumulator.errors += result.errors_delta as i64;
+                    if !result.changes.is_empty() {
+                        changed = true;
+                    }
+                    for change in result.changes.iter() {
+                        events.send(TerminalEvent::from(change));
+                        events.send(status_event(terminal, &accumulator, derive, &result.dbg_rule));
+                    }
+                    if let Some(sound_handle_ref) = audio_state.sound_handles.get(&result.sound_alias) {
+                        commands.spawn(AudioBundle {
+                            source: sound_handle_ref.clone(),
+                            settings: Default::default(),
+                        });
+                    }
+                    if debugger.enabled && !result.applied.is_empty() {
+                        let lines = debugger::describe(derive, c, &result.applied, &candidates);
+                        for event in debugger.show(derive, &lines, &result.applied) {
+                            events.send(event);
                         }
-                        if let Some(sound_handle_ref) = audio_state.sound_handles.get(&result.sound_alias) {
-                            commands.spawn(AudioBundle {
-                                source: sound_handle_ref.clone(),
-                                settings: Default::default(),
+                        next_state.set(AppState::Paused);
+                        stop = true;
+                        break;
+                    }
+                    let ending = match result.end.as_ref() {
+                        Some(end) => {
+                            let next = end.next.as_ref().and_then(|next| {
+                                let found = sibling_program(&program_file.0, next);
+                                if found.is_none() {
+                                    eprintln!("Cannot open program {}", next);
+                                }
+                                found.map(|path| path.to_string_lossy().to_string())
                             });
+                            Some(Ending::new(end, next))
+                        }
+                        None if derive.grammar.error_limit.is_some_and(|limit| accumulator.errors >= limit) =>
+                            Some(Ending::game_over()),
+                        None => None,
+                    };
+                    if let Some(ending) = ending {
+                        high_scores.record(&program_file.0, accumulator.score, accumulator.time);
+                        let table = high_scores.summary(&program_file.0, &derive.grammar);
+                        for event in outcome::overlay(terminal, &ending, accumulator.score, accumulator.time,
+                                                      accumulator.errors, &table) {
+                            events.send(event);
                         }
+                        outcome.0 = Some(ending);
+                        stop = true;
+                        break;
                     }
                 }
-                if time_step_code.0 == KeyCode::KeyT {
-                    cleared.push(key_code.clone());
+                if let Some(snapshot) = snapshot.filter(|_| changed) {
+                    derive.push_undo(snapshot);
+                }
+                if stop {
+                    break;
                 }
             }
             cleared.iter().for_each(|input| {
@@ -330,6 +781,28 @@ fn grammar_derivation_system(time_step_code: In<KeyCode>,
     }
 }
 
+/// Status line with the score, time and errors of the game, its variables and the last rule
+/// applied.
+fn status_event(terminal: &Terminal, accumulator: &RewardAccumulator, derive: &Derivation, rule: &str) -> TerminalEvent {
+    let mut msg_left = format!("Score: {} Time: {} Errors: {}",
+                               accumulator.score,
+                               accumulator.time,
+                               accumulator.errors);
+    for (name, value) in derive.variables() {
+        msg_left.push_str(&format!(" {}: {}", name, value));
+    }
+    msg_left.push_str(&format!(" Seed: {}", derive.seed()));
+    let msg_pad = std::iter::repeat(" ")
+        .take((terminal.cols - 2).saturating_sub(msg_left.chars().count() + rule.chars().count()))
+        .collect::<String>();
+    TerminalEvent {
+        row: 0,
+        col: 0,
+        s: format!(" {}{}{} ", msg_left, msg_pad, rule),
+        attr: (Color::WHITE, Color::BLACK),
+    }
+}
+
 fn grammar_derivation_system_t() -> KeyCode {
     KeyCode::KeyT
 }
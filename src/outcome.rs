@@ -0,0 +1,69 @@
+use bevy::color::palettes::basic::{BLUE, GREEN, RED};
+use bevy::prelude::{Color, Resource};
+
+use crate::grammar::{GameEnd, GameResult};
+use crate::terminal::{Terminal, TerminalEvent};
+
+/// End of a game: no more steps are taken until the player restarts the program or leaves it.
+#[derive(Resource, Default)]
+pub struct Outcome(pub Option<Ending>);
+
+pub struct Ending {
+    pub title: String,
+    pub text: Option<String>,
+    /// Program to go on with instead of restarting this one.
+    pub next: Option<String>,
+    back: Color,
+}
+
+impl Ending {
+    pub fn game_over() -> Self {
+        Ending { title: "Game over".to_string(), text: None, next: None, back: Color::Srgba(RED) }
+    }
+
+    /// Ending declared by a rule; `next` is the program it names, already located.
+    pub fn new(end: &GameEnd, next: Option<String>) -> Self {
+        let (title, back) = match end.result {
+            GameResult::Win => ("You win", GREEN),
+            GameResult::Lose => ("Game over", RED),
+            GameResult::Level => ("Level complete", BLUE),
+        };
+        Ending { title: title.to_string(), text: end.text.clone(), next, back: Color::Srgba(back) }
+    }
+}
+
+/// Box in the middle of the terminal with the ending, the reward of the game, the high
+/// scores and the keys leading on.
+pub fn overlay(terminal: &Terminal, ending: &Ending, score: i64, time: i64, errors: i64,
+               high_scores: &[String]) -> Vec<TerminalEvent> {
+    let mut lines = vec![ending.title.to_uppercase(), String::new()];
+    if let Some(text) = ending.text.as_ref() {
+        lines.extend([text.clone(), String::new()]);
+    }
+    lines.extend([format!("Score: {}  Time: {}  Errors: {}", score, time, errors), String::new()]);
+    if !high_scores.is_empty() {
+        lines.extend(high_scores.iter().cloned());
+        lines.push(String::new());
+    }
+    lines.push(format!("[space] {}  [q] menu  [e] quit", if ending.next.is_some() { "next" } else { "restart" }));
+    centred_box(terminal, &lines, (terminal.rows.saturating_sub(lines.len() + 2) / 2).max(1), ending.back)
+}
+
+/// Lines centred in a box of a colour starting at a row, with a blank line above and below.
+pub fn centred_box(terminal: &Terminal, lines: &[String], top: usize, back: Color) -> Vec<TerminalEvent> {
+    let width = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) + 4;
+    let left = terminal.cols.saturating_sub(width) / 2;
+    let blank = String::new();
+    std::iter::once(&blank).chain(lines.iter()).chain(std::iter::once(&blank))
+        .enumerate()
+        .map(|(i, line)| {
+            let pad = width.saturating_sub(line.chars().count());
+            TerminalEvent {
+                row: top + i,
+                col: left,
+                s: format!("{}{}{}", " ".repeat(pad / 2), line, " ".repeat(pad - pad / 2)),
+                attr: (Color::WHITE, back),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use bevy::asset::io::{AssetSource, AssetSourceBuilder};
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
+use bevy::asset::io::Reader;
+use bevy::prelude::*;
+
+use crate::grammar::{resolve_program, Grammar2D, GrammarError};
+
+/// Asset source serving programs from the directory of the start program.
+pub const PROGRAM_SOURCE: &str = "program";
+
+const FILE_DEBOUNCE_WAIT_TIME: Duration = Duration::from_millis(300);
+
+/// Loaded grammar program; problems found while loading are kept so they can be shown.
+#[derive(Asset, TypePath)]
+pub struct Program(pub Result<Grammar2D, GrammarError>);
+
+struct ProgramLoader {
+    root: PathBuf,
+}
+
+impl AssetLoader for ProgramLoader {
+    type Asset = Program;
+    type Settings = ();
+    type Error = std::io::Error;
+
+    async fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader<'_>,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext<'_>,
+    ) -> Result<Program, Self::Error> {
+        let mut content = Vec::new();
+        reader.read_to_end(&mut content).await?;
+        let filename = self.root.join(load_context.path()).to_string_lossy().to_string();
+        // included files within the program source are read as dependencies, so that editing
+        // them reloads the program too; others are left to the grammar to read
+        let mut files = HashMap::<PathBuf, Vec<u8>>::default();
+        let mut pending = vec![(filename.clone(), content.clone())];
+        while let Some((file, file_content)) = pending.pop() {
+            for path in Grammar2D::included_files(&file, &file_content) {
+                let canonical = path.canonicalize().unwrap_or(path.clone());
+                let Some(asset_path) = program_asset_path(&self.root, &path.to_string_lossy()) else {
+                    continue;
+                };
+                if files.contains_key(&canonical) {
+                    continue;
+                }
+                if let Ok(included) = load_context.read_asset_bytes(asset_path).await {
+                    files.insert(canonical, included.clone());
+                    pending.push((path.to_string_lossy().to_string(), included));
+                }
+            }
+        }
+        Ok(Program(Grammar2D::load_from(&filename, &content, files)))
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cfg", "cfg.gz"]
+    }
+}
+
+/// Watched asset source rooted at `root`; has to be registered before the `AssetPlugin`.
+pub fn program_source(root: &Path) -> AssetSourceBuilder {
+    let root = root.to_string_lossy().to_string();
+    AssetSource::build()
+        .with_reader(AssetSource::get_default_reader(root.clone()))
+        .with_watcher(AssetSource::get_default_watcher(root, FILE_DEBOUNCE_WAIT_TIME))
+}
+
+/// Asset path of a program file, if it lies within the program source.
+pub fn program_asset_path(root: &Path, program_file: &str) -> Option<String> {
+    let path = resolve_program(Path::new(program_file))?.canonicalize().ok()?;
+    let relative = path.strip_prefix(root).ok()?;
+    Some(format!("{}://{}", PROGRAM_SOURCE, relative.to_string_lossy()))
+}
+
+pub struct ProgramPlugin {
+    root: PathBuf,
+}
+
+impl ProgramPlugin {
+    pub fn new(root: PathBuf) -> Self {
+        ProgramPlugin { root }
+    }
+}
+
+impl Plugin for ProgramPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_asset::<Program>()
+            .register_asset_loader(ProgramLoader { root: self.root.clone() });
+    }
+}
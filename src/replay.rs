@@ -0,0 +1,151 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use bevy::prelude::Resource;
+
+use crate::grammar::Key;
+
+/// Line of a replay file.
+pub enum Record {
+    /// A derivation started: `start <seed> <rows> <cols> <program>`.
+    Start { seed: u64, rows: usize, cols: usize, program: String },
+    /// A key passed to the derivation by a clock at its tick: `<tick> <clock> <key>`.
+    Step { tick: u64, clock: char, key: Key },
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Record::Start { seed, rows, cols, program } =>
+                write!(f, "start {} {} {} {}", seed, rows, cols, program),
+            Record::Step { tick, clock, key } => write!(f, "{} {} {}", tick, clock, key),
+        }
+    }
+}
+
+impl Record {
+    fn parse(line: &str) -> Option<Record> {
+        if let Some(start) = line.strip_prefix("start ") {
+            let mut it = start.splitn(4, ' ');
+            return Some(Record::Start {
+                seed: it.next()?.parse().ok()?,
+                rows: it.next()?.parse().ok()?,
+                cols: it.next()?.parse().ok()?,
+                program: it.next()?.to_string(),
+            });
+        }
+        let mut it = line.splitn(3, ' ');
+        let tick = it.next()?.parse().ok()?;
+        let mut clock = it.next()?.chars();
+        match (clock.next(), clock.next()) {
+            (Some(clock), None) => Some(Record::Step { tick, clock, key: Key::from_name(it.next()?)? }),
+            _ => None,
+        }
+    }
+}
+
+/// Recording of the keys passed to derivations (`--record`) or playing them back in their
+/// place (`--replay`). Ticks are counted per clock from the start of each derivation.
+/// Records are buffered, as the fast clock alone passes hundreds of keys a second.
+#[derive(Resource, Default)]
+pub struct Replay {
+    recording: Option<BufWriter<File>>,
+    playing: Option<VecDeque<Record>>,
+    ticks: HashMap<char, u64>,
+}
+
+impl Replay {
+    pub fn record(path: &str) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        writeln!(file, "# zero replay")?;
+        Ok(Replay { recording: Some(file), ..Default::default() })
+    }
+
+    pub fn play(path: &str) -> io::Result<Self> {
+        let mut records = VecDeque::<Record>::default();
+        for (idx, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            records.push_back(Record::parse(&line).ok_or_else(|| io::Error::new(
+                io::ErrorKind::InvalidData, format!("{}:{}: malformed record `{}`", path, idx + 1, line)))?);
+        }
+        Ok(Replay { playing: Some(records), ..Default::default() })
+    }
+
+    /// Program the replay starts with.
+    pub fn program(&self) -> Option<&str> {
+        self.playing.iter().flatten().find_map(|record| match record {
+            Record::Start { program, .. } => Some(program.as_str()),
+            _ => None,
+        })
+    }
+
+    fn write(&mut self, record: Record, flush: bool) {
+        if let Some(file) = self.recording.as_mut() {
+            let written = writeln!(file, "{}", record).and_then(|_| if flush { file.flush() } else { Ok(()) });
+            if let Err(e) = written {
+                eprintln!("cannot record: {}", e);
+                self.recording = None;
+            }
+        }
+    }
+
+    /// Writes out the buffered records.
+    pub fn flush(&mut self) {
+        if let Some(Err(e)) = self.recording.as_mut().map(|file| file.flush()) {
+            eprintln!("cannot record: {}", e);
+            self.recording = None;
+        }
+    }
+
+    /// Seed of a derivation about to start: the recorded one when playing, else `seed`.
+    pub fn start(&mut self, program: &str, rows: usize, cols: usize, seed: u64) -> u64 {
+        self.ticks.clear();
+        if let Some(records) = self.playing.as_mut() {
+            // keys left over from the previous derivation are dropped
+            while let Some(record) = records.pop_front() {
+                if let Record::Start { seed, rows: r, cols: c, .. } = record {
+                    if (r, c) != (rows, cols) {
+                        eprintln!("replay recorded on a {}x{} grid runs on {}x{}, it may diverge",
+                                  r, c, rows, cols);
+                    }
+                    return seed;
+                }
+            }
+            eprintln!("replay finished");
+            self.playing = None;
+        }
+        self.write(Record::Start { seed, rows, cols, program: program.to_string() }, true);
+        seed
+    }
+
+    /// Advances the tick of a clock and returns the keys to pass to the derivation: those
+    /// recorded up to this tick when playing, else `keys`, which are recorded.
+    pub fn keys(&mut self, clock: char, keys: Vec<Key>) -> Vec<Key> {
+        let tick = self.ticks.entry(clock).or_insert(0);
+        *tick += 1;
+        let tick = *tick;
+        if let Some(records) = self.playing.as_mut() {
+            let mut due = Vec::<Key>::default();
+            while let Some(Record::Step { tick: t, clock: c, key }) = records.front() {
+                if *c != clock || *t > tick {
+                    break;
+                }
+                due.push(*key);
+                records.pop_front();
+            }
+            if records.is_empty() {
+                eprintln!("replay finished");
+                self.playing = None;
+            }
+            return due;
+        }
+        for key in keys.iter() {
+            self.write(Record::Step { tick, clock, key: *key }, false);
+        }
+        keys
+    }
+}
@@ -0,0 +1,63 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+
+/// Game written to the file of `--save`: the program running, the reward of the game so far
+/// and the state of its derivation (see `Derivation::save`).
+pub struct SavedGame {
+    pub program: String,
+    pub score: i64,
+    pub time: i64,
+    pub errors: i64,
+    pub state: Vec<String>,
+}
+
+impl SavedGame {
+    /// Reads a saved game; `None` if the file does not exist.
+    pub fn read(path: &str) -> io::Result<Option<SavedGame>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut saved = SavedGame { program: String::new(), score: 0, time: 0, errors: 0, state: vec![] };
+        for (idx, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let malformed = || io::Error::new(
+                io::ErrorKind::InvalidData, format!("{}:{}: malformed line `{}`", path, idx + 1, line));
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(program) = line.strip_prefix("program ") {
+                saved.program = program.to_string();
+            } else if let Some(reward) = line.strip_prefix("reward ") {
+                let reward = reward.split(' ').map(|n| n.parse::<i64>()).collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| malformed())?;
+                let [score, time, errors] = reward[..] else {
+                    return Err(malformed());
+                };
+                (saved.score, saved.time, saved.errors) = (score, time, errors);
+            } else {
+                saved.state.push(line);
+            }
+        }
+        if saved.program.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: no program saved", path)));
+        }
+        Ok(Some(saved))
+    }
+
+    /// Writes the game next to the file and renames it, so that a failed write leaves the
+    /// previous save intact.
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let temp = format!("{}.tmp", path);
+        let mut file = File::create(&temp)?;
+        writeln!(file, "# zero save")?;
+        writeln!(file, "program {}", self.program)?;
+        writeln!(file, "reward {} {} {}", self.score, self.time, self.errors)?;
+        for line in self.state.iter() {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+        fs::rename(temp, path)
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use bevy::prelude::Resource;
+
+use crate::grammar::{resolve_program, sibling_program, Grammar2D};
+
+/// Scores kept per program.
+const TABLE_SIZE: usize = 10;
+
+// scores shown on the pause screen and at the end of a game
+const SHOWN: usize = 5;
+
+/// Score of a game with the time it was played for and when it ended, in seconds since 1970.
+#[derive(Clone, Copy)]
+pub struct Entry {
+    pub score: i64,
+    pub time: i64,
+    pub date: u64,
+}
+
+impl Entry {
+    /// Date and time (UTC) the game ended, e.g. `2024-05-01 18:30`.
+    pub fn date(&self) -> String {
+        let days = (self.date / 86400) as i64;
+        let minutes = (self.date % 86400) / 60;
+        // civil date of a day count, after Howard Hinnant's `civil_from_days`
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes / 60, minutes % 60)
+    }
+}
+
+/// High scores of the programs played, kept in a file. A program is told apart by its path
+/// and a hash of its content, so that editing a program starts a new table.
+#[derive(Resource, Default)]
+pub struct HighScores {
+    path: Option<PathBuf>,
+    tables: HashMap<String, Vec<Entry>>,
+    last: Option<(String, Entry)>,
+    /// Scores shown on the pause screen, kept while it lasts, and the cells they cover.
+    pub paused: Option<Vec<String>>,
+    pub shown: Vec<(usize, usize)>,
+}
+
+// FNV-1a, stable across builds unlike the hasher of the standard library
+fn hash(content: &[u8]) -> u64 {
+    content.iter().fold(0xcbf29ce484222325, |h, &b| (h ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+impl HighScores {
+    /// File of `--scores`, else `zero/scores.txt` in the user data directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let data = std::env::var_os("XDG_DATA_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+        Some(data.join("zero").join("scores.txt"))
+    }
+
+    /// High scores of a file; a missing file has none.
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut scores = HighScores { path: Some(path.clone()), ..Default::default() };
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(scores),
+            Err(e) => return Err(e),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let mut it = line.splitn(5, ' ');
+            let (Some(hash), Some(score), Some(time), Some(date), Some(program)) =
+                (it.next(), it.next(), it.next(), it.next(), it.next()) else {
+                continue;
+            };
+            let (Ok(score), Ok(time), Ok(date)) = (score.parse(), time.parse(), date.parse()) else {
+                continue;
+            };
+            scores.tables.entry(format!("{} {}", hash, program)).or_default().push(Entry { score, time, date });
+        }
+        Ok(scores)
+    }
+
+    // program path with the hash of its content
+    fn key(program: &str) -> Option<String> {
+        let path = resolve_program(Path::new(program))?;
+        let content = fs::read(&path).ok()?;
+        let path = path.canonicalize().unwrap_or(path);
+        Some(format!("{:016x} {}", hash(&content), path.to_string_lossy()))
+    }
+
+    /// High scores of a program, best first.
+    pub fn table(&self, program: &str) -> &[Entry] {
+        Self::key(program).and_then(|key| self.tables.get(&key)).map_or(&[], |table| table.as_slice())
+    }
+
+    /// Adds the score of a game that ended and writes the file; games without score and the
+    /// same game recorded twice are left out.
+    pub fn record(&mut self, program: &str, score: i64, time: i64) {
+        let Some(key) = Self::key(program).filter(|_| score > 0) else {
+            return;
+        };
+        let date = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let entry = Entry { score, time, date };
+        if self.last.as_ref().is_some_and(|(k, e)| *k == key && (e.score, e.time) == (score, time)) {
+            return;
+        }
+        self.last = Some((key.clone(), entry));
+        let table = self.tables.entry(key).or_default();
+        table.push(entry);
+        table.sort_by_key(|entry| (-entry.score, entry.time));
+        table.truncate(TABLE_SIZE);
+        if let Err(e) = self.write() {
+            eprintln!("cannot write high scores: {}", e);
+        }
+    }
+
+    /// Lines of the best scores of a program, or of the best score of each program it
+    /// navigates to, as in the menu.
+    pub fn summary(&self, program: &str, grammar: &Grammar2D) -> Vec<String> {
+        let mut targets = grammar.written_rules().into_iter()
+            .filter(|rule| rule.sound == '>')
+            .map(|rule| Grammar2D::navigation_target(&rule.lhs_all))
+            .collect::<Vec<_>>();
+        targets.dedup();
+        let mut lines = Vec::<String>::default();
+        for target in targets {
+            let Some(path) = sibling_program(program, target) else {
+                continue;
+            };
+            if let Some(best) = self.table(&path.to_string_lossy()).first() {
+                lines.push(format!("{:<16} {:>8}  {}", target, best.score, best.date()));
+            }
+        }
+        if lines.is_empty() {
+            lines = self.table(program).iter().take(SHOWN).enumerate()
+                .map(|(i, entry)| format!("{}. {:>8}  time {:>5}  {}", i + 1, entry.score, entry.time, entry.date()))
+                .collect();
+        }
+        if !lines.is_empty() {
+            lines.insert(0, "HIGH SCORES".to_string());
+        }
+        lines
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let Some(path) = self.path.as_ref() else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut keys = self.tables.keys().collect::<Vec<_>>();
+        keys.sort();
+        let mut file = File::create(path)?;
+        for key in keys {
+            let (hash, program) = key.split_once(' ').unwrap_or((key, ""));
+            for entry in self.tables[key].iter() {
+                writeln!(file, "{} {} {} {} {}", hash, entry.score, entry.time, entry.date, program)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,10 @@
+use bevy::color::palettes::basic;
 use bevy::prelude::*;
 use bevy::window::WindowResized;
 use bevy::window::PrimaryWindow;
+use unicode_width::UnicodeWidthChar;
 use crate::{MIN_CHAR_HEIGHT, MIN_CHAR_WIDTH};
+use crate::derivation::CellChange;
 
 #[derive(Component)]
 pub struct Terminal {
@@ -13,6 +16,12 @@ pub struct Terminal {
     pub color_pair: (Color, Color),
 }
 
+/// Colours of the palette indices of cell changes.
+const PALETTE: [Srgba; 8] = [
+    basic::BLACK, basic::RED, basic::GREEN, basic::YELLOW,
+    basic::BLUE, basic::FUCHSIA, basic::AQUA, basic::WHITE,
+];
+
 //const FONT_PATH: &str = "fonts/DejaVuSansMono-Bold.ttf";
 //const FONT_PATH: &str = "fonts/FreeMonoBold.otf";
 const FONT_PATH: &str = "fonts/iosevka-term-regular.ttf";
@@ -51,6 +60,19 @@ impl TerminalEvent {
     }
 }
 
+impl From<&CellChange> for TerminalEvent {
+    fn from(change: &CellChange) -> Self {
+        let colour = |index: u8, default: usize| Color::Srgba(
+            *PALETTE.get(index as usize).unwrap_or(&PALETTE[default]));
+        Self {
+            row: change.row,
+            col: change.col,
+            s: change.c.to_string(),
+            attr: (colour(change.fore, 7), colour(change.back, 0)),
+        }
+    }
+}
+
 #[derive(Event)]
 pub struct TerminalReady;
 
@@ -248,6 +270,13 @@ impl Terminal {
         self.color_pair = color_pair;
     }
 
+    /// Columns taken by a character on the grid: 2 for East Asian wide characters, else 1.
+    pub fn width(c: char) -> usize {
+        c.width().unwrap_or(1).max(1)
+    }
+
+    /// Puts a character at a cell. A wide character also covers the cell to its right, which
+    /// is left empty, and is replaced by a space when it does not fit or is partly overwritten.
     pub fn mvaddch(&self, fore: &mut Text, back: &mut Text, row: usize, col: usize, c: char) {
         let idx = (self.cols + 1) * row + col;
         fn addch(section: &mut TextSection, c: char, col: Color) {
@@ -255,8 +284,29 @@ impl Terminal {
             section.style.color = col;
         }
         if (idx < back.sections.len()) & (idx < fore.sections.len()) {
+            let wide = |section: &TextSection| section.value.chars().next().is_some_and(|c| Self::width(c) > 1);
+            // a wide character whose half is overwritten disappears
+            if col > 0 && fore.sections[idx].value.is_empty() {
+                fore.sections[idx - 1].value = String::from(' ');
+            }
+            if col + 1 < self.cols && wide(&fore.sections[idx]) {
+                fore.sections[idx + 1].value = String::from(' ');
+            }
             addch(&mut back.sections[idx], '█', self.color_pair.1);
-            addch(&mut fore.sections[idx], c, self.color_pair.0);
+            if Self::width(c) > 1 {
+                if col + 1 < self.cols {
+                    addch(&mut fore.sections[idx], c, self.color_pair.0);
+                    addch(&mut back.sections[idx + 1], '█', self.color_pair.1);
+                    if col + 2 < self.cols && fore.sections[idx + 2].value.is_empty() {
+                        fore.sections[idx + 2].value = String::from(' ');
+                    }
+                    fore.sections[idx + 1].value = String::new();
+                } else {
+                    addch(&mut fore.sections[idx], ' ', self.color_pair.0);
+                }
+            } else {
+                addch(&mut fore.sections[idx], c, self.color_pair.0);
+            }
         }
     }
 
@@ -264,7 +314,7 @@ impl Terminal {
         let mut cur: (usize, usize) = (row, col);
         for c in s.chars() {
             self.mvaddch(fore, back, cur.0, cur.1, c);
-            cur.1 += 1;
+            cur.1 += Self::width(c);
             if cur.1 >= self.cols {
                 cur.0 += 1;
                 cur.1 = 0;
@@ -272,4 +322,34 @@ impl Terminal {
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(fore: &Text) -> Vec<&str> {
+        fore.sections[..4].iter().map(|section| section.value.as_str()).collect()
+    }
+
+    // a wide character covers the cell to its right and vanishes when either half is overwritten
+    #[test]
+    fn wide_characters_cover_two_cells() {
+        let terminal = Terminal {
+            rows: 1, cols: 4, font_size: 10.0, font_path: String::new(), font_scale: (1.0, 1.0),
+            color_pair: (Color::WHITE, Color::BLACK),
+        };
+        let blank = || Text::from_sections((0..5).map(|_| TextSection::new(" ", TextStyle::default())));
+        let (mut fore, mut back) = (blank(), blank());
+        terminal.mvaddch(&mut fore, &mut back, 0, 0, '中');
+        assert_eq!(row(&fore), ["中", "", " ", " "]);
+        terminal.mvaddch(&mut fore, &mut back, 0, 1, 'x');
+        assert_eq!(row(&fore), [" ", "x", " ", " "]);
+        terminal.mvaddch(&mut fore, &mut back, 0, 1, '中');
+        terminal.mvaddch(&mut fore, &mut back, 0, 0, '中');
+        assert_eq!(row(&fore), ["中", "", " ", " "]);
+        terminal.mvaddch(&mut fore, &mut back, 0, 3, '中');
+        assert_eq!(row(&fore), ["中", "", " ", " "]);
+        terminal.mvaddch(&mut fore, &mut back, 0, 2, '╬');
+        assert_eq!(row(&fore), ["中", "", "╬", " "]);
+    }
+}